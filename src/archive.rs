@@ -0,0 +1,77 @@
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// 下载记录文件，每行一个已下载视频的 key
+///
+/// 普通视频的 key 为 `{bvid}_{cid}`，番剧为 `ep{ep_id}`
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    keys: Mutex<HashSet<String>>,
+}
+
+impl Archive {
+    /// 读取下载记录，文件不存在时视为空记录
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let keys = match fs::read_to_string(&path) {
+            Ok(s) => s
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            keys: Mutex::new(keys),
+        })
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.lock().unwrap().contains(key)
+    }
+
+    /// 记录一个已完成的下载，并立即写入文件
+    pub fn record(&self, key: &str) -> io::Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        if !keys.insert(key.to_string()) {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", key)
+    }
+}
+
+#[cfg(test)]
+mod archive_test {
+    use super::*;
+
+    #[test]
+    fn record_and_reopen() {
+        let path = std::env::temp_dir().join(format!("bili-dl-archive-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let archive = Archive::open(path.clone()).unwrap();
+        assert!(!archive.contains("ep830937"));
+        archive.record("ep830937").unwrap();
+        archive.record("ep830937").unwrap();
+        archive.record("BV1ub421J7vH_1620148896").unwrap();
+
+        let archive = Archive::open(path.clone()).unwrap();
+        assert!(archive.contains("ep830937"));
+        assert!(archive.contains("BV1ub421J7vH_1620148896"));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
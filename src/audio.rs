@@ -0,0 +1,288 @@
+use crate::http::download::{Details, Sidecar, Task, TaskKey};
+use crate::http::fetch::Metadata;
+use crate::http::Result;
+use crate::http::{check_code, client::Client, url_regex};
+use crate::progress::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+use std::path::PathBuf;
+use AudioID::*;
+
+const API: &str = "https://www.bilibili.com/audio/music-service-c/web";
+
+enum AudioID {
+    /// 单曲 `au`
+    SongID(i64),
+    /// 歌单 `am`
+    MenuID(i64),
+}
+
+/// 音频区的歌曲
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SongInfo {
+    id: i64,
+    title: String,
+    /// 歌手
+    #[serde(default)]
+    author: String,
+    /// 上传者
+    #[serde(default)]
+    uname: String,
+    /// 封面
+    #[serde(default)]
+    cover: String,
+    /// LRC 歌词的链接
+    #[serde(default)]
+    lyric: String,
+    /// 时长，单位为秒
+    #[serde(default)]
+    duration: u64,
+    /// 发布时间的时间戳，单位为毫秒
+    #[serde(default)]
+    passtime: i64,
+}
+
+/// 音频区的歌单
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MenuInfo {
+    #[serde(rename = "menuId")]
+    menu_id: i64,
+    title: String,
+    #[serde(default)]
+    intro: String,
+    #[serde(default)]
+    cover: String,
+    #[serde(default)]
+    songs: Vec<SongInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SongPage {
+    #[serde(rename = "pageCount")]
+    page_count: u32,
+    #[serde(default)]
+    data: Vec<SongInfo>,
+}
+
+/// 歌曲写入文件的标签
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tags {
+    pub title: String,
+    pub artist: String,
+    /// 从歌单下载时为歌单名
+    pub album: Option<String>,
+}
+
+/// 歌曲的下载链接
+#[derive(Debug, Clone)]
+pub struct SongStream {
+    pub urls: Vec<String>,
+    /// 如 `320K`、`无损`
+    pub quality: String,
+}
+
+impl SongInfo {
+    fn link(&self) -> String {
+        format!("https://www.bilibili.com/audio/au{}", self.id)
+    }
+
+    fn artist(&self) -> &str {
+        if self.author.is_empty() {
+            &self.uname
+        } else {
+            &self.author
+        }
+    }
+
+    /// the task of the song, saved as `歌手 - 歌名` with its cover and lyrics
+    fn task(&self, number: usize, album: Option<&str>) -> Task {
+        // several artists are joined by slashes, which must not become folders
+        let title = match self.artist() {
+            "" => self.title.replace('/', "_"),
+            artist => format!("{} - {}", artist, self.title).replace('/', "_"),
+        };
+        let mut task = Task::new(self.link(), title, number, TaskKey::song(self.id));
+        task.details = Details {
+            duration: (self.duration > 0).then_some(self.duration),
+            pub_time: (self.passtime > 0).then_some(self.passtime / 1000),
+            ..Default::default()
+        };
+        task.song = Some(Tags {
+            title: self.title.clone(),
+            artist: self.artist().to_owned(),
+            album: album.map(str::to_owned),
+        });
+        for (url, ext) in [(&self.cover, "jpg"), (&self.lyric, "lrc")] {
+            if !url.is_empty() {
+                task.sidecars.push(Sidecar::Remote {
+                    // titles like `Ver.2` must keep the part after the dot
+                    path: PathBuf::from(format!("{}.{}", task.title, ext)),
+                    url: url.clone(),
+                });
+            }
+        }
+        task
+    }
+}
+
+impl Client {
+    async fn fetch_audio_api(&self, url: &str) -> Result<Value> {
+        let mut resp: Value = self.send(self.get(url)).await?.json().await?;
+        check_code(&resp)?;
+        Ok(resp["data"].take())
+    }
+
+    async fn fetch_song_info(&self, id: i64) -> Result<SongInfo> {
+        let data = self
+            .fetch_audio_api(&format!("{}/song/info?sid={}", API, id))
+            .await?;
+        Ok(serde_json::from_value(data)?)
+    }
+
+    /// the playlist and every song in it
+    async fn fetch_menu_info(&self, id: i64) -> Result<MenuInfo> {
+        let data = self
+            .fetch_audio_api(&format!("{}/menu/info?sid={}", API, id))
+            .await?;
+        let mut menu: MenuInfo = serde_json::from_value(data)?;
+        let mut pn = 1;
+        loop {
+            let url = format!("{}/song/of-menu?sid={}&pn={}&ps=100", API, id, pn);
+            let page: SongPage = serde_json::from_value(self.fetch_audio_api(&url).await?)?;
+            menu.songs.extend(page.data);
+            if pn >= page.page_count {
+                break;
+            }
+            pn += 1;
+        }
+        Ok(menu)
+    }
+
+    /// Fetch the download urls of a song in the best quality the user can get
+    pub async fn fetch_song_stream(&self, link: &str) -> Result<SongStream> {
+        let Ok(SongID(id)) = audio_url_parser(link) else {
+            return Err("解析歌曲 id 失败".into());
+        };
+        let url = format!("{}/url?sid={}&privilege=2&quality=2", API, id);
+        let data = self.fetch_audio_api(&url).await?;
+        let urls: Vec<String> = serde_json::from_value(data["cdns"].clone())?;
+        if urls.is_empty() {
+            return Err("未找到音频流".into());
+        }
+        let quality = match data["type"].as_i64() {
+            Some(-1) => "试听",
+            Some(0) => "128K",
+            Some(1) => "192K",
+            Some(2) => "320K",
+            Some(3) => "无损",
+            _ => "未知",
+        };
+        Ok(SongStream {
+            urls,
+            quality: quality.to_owned(),
+        })
+    }
+
+    /// get a song, or every song of a playlist saved in a folder named after it
+    pub async fn get_audio(&self, url: &str, dir: &mut PathBuf) -> Result<(Metadata, Vec<Task>)> {
+        match audio_url_parser(url)? {
+            SongID(id) => {
+                let info = self.fetch_song_info(id).await?;
+                let task = info.task(1, None);
+                Ok((Metadata::Song(info), vec![task]))
+            }
+            MenuID(id) => {
+                let info = self.fetch_menu_info(id).await?;
+                info!(
+                    "获取歌单成功\n《{}》, 共{}首",
+                    &info.title,
+                    info.songs.len()
+                );
+                let tasks = info
+                    .songs
+                    .iter()
+                    .zip(1..)
+                    .map(|(song, n)| song.task(n, Some(&info.title)))
+                    .collect();
+                dir.push(&info.title);
+                Ok((Metadata::Menu(info), tasks))
+            }
+        }
+    }
+}
+
+/// whether the link is a song of the audio area
+pub fn is_song(url: &str) -> bool {
+    matches!(audio_url_parser(url), Ok(SongID(_)))
+}
+
+/// 从音频区链接匹配 au 号或 am 号
+fn audio_url_parser(url: &str) -> Result<AudioID> {
+    if let Some(id) = url_regex(r"/au(\d+)/", url) {
+        return Ok(SongID(id.parse()?));
+    }
+    if let Some(id) = url_regex(r"/am(\d+)/", url) {
+        return Ok(MenuID(id.parse()?));
+    }
+    Err("解析音频 id 失败".into())
+}
+
+#[cfg(test)]
+mod audio_test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn song_task() {
+        let song: SongInfo = serde_json::from_value(json!({
+            "id": 15664, "title": "歌名", "author": "歌手", "uname": "上传者",
+            "cover": "https://example.com/a.jpg", "lyric": "https://example.com/a.lrc",
+            "duration": 240, "passtime": 1500000000000i64,
+        }))
+        .unwrap();
+        let task = song.task(2, Some("歌单"));
+        assert_eq!(&*task.key, "au15664");
+        let duet: SongInfo = serde_json::from_value(json!({
+            "id": 15665, "title": "歌名 Ver.2", "author": "洛天依/言和",
+            "cover": "https://example.com/b.jpg",
+        }))
+        .unwrap();
+        let duet = duet.task(1, None);
+        assert_eq!(duet.title, "洛天依_言和 - 歌名 Ver.2");
+        assert_eq!(
+            duet.sidecars[0],
+            Sidecar::Remote {
+                path: PathBuf::from("洛天依_言和 - 歌名 Ver.2.jpg"),
+                url: "https://example.com/b.jpg".into(),
+            }
+        );
+        assert_eq!(task.title, "歌手 - 歌名");
+        assert_eq!(task.number, 2);
+        assert_eq!(task.details.pub_time, Some(1500000000));
+        assert_eq!(
+            task.song,
+            Some(Tags {
+                title: "歌名".into(),
+                artist: "歌手".into(),
+                album: Some("歌单".into()),
+            })
+        );
+        assert_eq!(
+            task.sidecars[1],
+            Sidecar::Remote {
+                path: PathBuf::from("歌手 - 歌名.lrc"),
+                url: "https://example.com/a.lrc".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn audio_urls() {
+        let url = "https://www.bilibili.com/audio/au15664";
+        assert!(matches!(audio_url_parser(url), Ok(SongID(15664))));
+        assert!(is_song(url));
+        let url = "https://www.bilibili.com/audio/am10624?type=3";
+        assert!(matches!(audio_url_parser(url), Ok(MenuID(10624))));
+        assert!(!is_song(url));
+    }
+}
@@ -1,9 +1,14 @@
-use crate::http::download::Task;
+use crate::chapter::OpEd;
+use crate::http::download::{Access, Details, Sidecar, Task, TaskKey};
+use crate::http::fetch::Metadata;
 use crate::http::Result;
-use crate::http::{client::Client, url_regex};
+use crate::http::{check_code, client::Client, url_regex};
+use crate::nfo;
+use crate::progress::{error, info};
+use chrono::DateTime;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use BangumiID::*;
 
 enum BangumiID {
@@ -21,9 +26,66 @@ pub struct BangumiInfo {
     season_id: i64,
     media_id: i64,
     episodes: Vec<Episode>,
+    /// PV、OP/ED、特别篇等正片以外的分组
+    #[serde(default)]
+    section: Vec<Section>,
+    /// 同系列的全部季度，包括当前季度
+    #[serde(default)]
+    seasons: Vec<Season>,
+    series: Option<Series>,
     season_title: String,
     /// 总集数
     total: i32,
+    /// 简介
+    #[serde(default)]
+    evaluate: String,
+    /// 海报
+    #[serde(default)]
+    cover: String,
+    /// 背景图
+    #[serde(default)]
+    bkg_cover: String,
+    publish: Option<Publish>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Publish {
+    /// 开播时间，如 `2024-01-01 00:00:00`
+    #[serde(default)]
+    pub_time: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Section {
+    /// 分组名称，如 `PV&其他`
+    title: String,
+    #[serde(default)]
+    episodes: Vec<Episode>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Season {
+    season_id: i64,
+    /// 如 `第二季`、`剧场版`
+    season_title: String,
+    /// 1 为番剧，2 为电影
+    #[serde(default)]
+    season_type: i32,
+}
+
+impl Season {
+    /// whether this is a TV season rather than a movie or an OVA, which are saved as specials
+    fn is_tv(&self) -> bool {
+        self.season_type != 2
+            && !["剧场版", "电影", "OVA", "OAD"]
+                .iter()
+                .any(|k| self.season_title.contains(k))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Series {
+    series_title: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,7 +96,286 @@ pub struct Episode {
     #[serde(rename = "title")]
     pub ep_num: String,
     pub badge_type: i32,
+    /// 会员、预告等标记
+    #[serde(default)]
+    badge: String,
+    /// 时长，单位为毫秒
+    #[serde(default)]
+    duration: u64,
+    #[serde(default)]
+    pub_time: i64,
     status: i32,
+    /// 片头和片尾的时间
+    #[serde(default)]
+    skip: Skip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Skip {
+    op: Option<SkipRange>,
+    ed: Option<SkipRange>,
+}
+
+/// 单位为秒，没有片头或片尾时均为 0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SkipRange {
+    start: u64,
+    end: u64,
+}
+
+impl Skip {
+    fn op_ed(&self) -> OpEd {
+        let range = |r: Option<SkipRange>| {
+            r.filter(|r| r.end > r.start)
+                .map(|r| (r.start as f64, r.end as f64))
+        };
+        OpEd {
+            op: range(self.op),
+            ed: range(self.ed),
+        }
+    }
+}
+
+impl Episode {
+    fn details(&self, is_extra: bool) -> Details {
+        Details {
+            duration: (self.duration > 0).then_some(self.duration / 1000),
+            pub_time: (self.pub_time > 0).then_some(self.pub_time),
+            badge: (!self.badge.is_empty()).then(|| self.badge.clone()),
+            group: None,
+            access: self.access(is_extra),
+        }
+    }
+
+    /// who can watch the episode, judged from its `status` and badge.
+    /// Extras like PVs are never previews of an episode
+    fn access(&self, is_extra: bool) -> Access {
+        if self.badge.contains("港澳台") || self.badge.contains("地区") {
+            Access::Region
+        } else if matches!(self.status, 12 | 13)
+            || self.badge.contains("会员")
+            || self.badge.contains("付费")
+        {
+            Access::Member
+        } else if !is_extra && (self.badge_type == 1 || self.badge == "预告") {
+            Access::Preview
+        } else {
+            Access::Free
+        }
+    }
+}
+
+/// 番剧的下载范围和保存方式
+#[derive(Debug, Clone)]
+pub struct BangumiOptions {
+    /// 下载同系列的全部季度，每季保存到单独的文件夹
+    pub all_seasons: bool,
+    /// 按 Jellyfin、Kodi 等媒体服务器的格式命名，并生成 NFO 和海报
+    pub media_server: bool,
+    /// 列出的剧集类型，其余的会被跳过
+    pub access: Vec<Access>,
+}
+
+impl Default for BangumiOptions {
+    fn default() -> Self {
+        Self {
+            all_seasons: false,
+            media_server: false,
+            access: vec![Access::Free, Access::Member, Access::Region],
+        }
+    }
+}
+
+/// where the episodes of a season are saved
+#[derive(Debug, Clone, Copy, Default)]
+struct Layout<'a> {
+    /// the folder and group of the season when every season is downloaded
+    season: Option<&'a str>,
+    media_server: Option<MediaServer<'a>>,
+}
+
+/// `Show/Season 01/Show - S01E03 - Title.mp4`, the extras of every season are in `Season 00`
+#[derive(Debug, Clone, Copy)]
+struct MediaServer<'a> {
+    /// the season the download started from, its title is the show name
+    show: &'a BangumiInfo,
+    /// the number among the TV seasons, 0 for a movie or an OVA whose episodes are all specials
+    season: u32,
+    /// the specials of the season are numbered from `specials + 1`
+    specials: u32,
+}
+
+impl BangumiInfo {
+    /// every episode with its section, `None` for the main episodes,
+    /// and its number from 1 within the main episodes or the extras
+    fn all_episodes(&self) -> impl Iterator<Item = (Option<&Section>, &Episode, u32)> {
+        let main = self.episodes.iter().zip(1..).map(|(ep, n)| (None, ep, n));
+        let extras = self
+            .section
+            .iter()
+            .flat_map(|s| s.episodes.iter().map(move |ep| (Some(s), ep)))
+            .zip(1..)
+            .map(|((s, ep), n)| (s, ep, n));
+        main.chain(extras)
+    }
+
+    /// the show name used by the media server layout
+    fn show_name(&self) -> &str {
+        self.series
+            .as_ref()
+            .map(|s| s.series_title.as_str())
+            .unwrap_or(&self.title)
+    }
+
+    /// the media server numbering of a season of this series. The specials of a season start
+    /// from 100 times its position in the series, so that those of every season can be kept in
+    /// `Season 00` without sharing a number however many seasons are downloaded at a time
+    fn media_server(&self, season_id: i64) -> MediaServer<'_> {
+        let position = self.seasons.iter().position(|s| s.season_id == season_id);
+        let season = match position {
+            Some(i) if !self.seasons[i].is_tv() => 0,
+            Some(i) => self.seasons[..=i].iter().filter(|s| s.is_tv()).count() as u32,
+            None => 1,
+        };
+        MediaServer {
+            show: self,
+            season,
+            specials: (position.unwrap_or_default() as u32 + 1) * 100,
+        }
+    }
+
+    fn task(
+        &self,
+        (section, ep, number): (Option<&Section>, &Episode, u32),
+        layout: Layout,
+    ) -> Task {
+        let key = TaskKey::episode(ep.ep_id);
+        let mut task = match layout.media_server {
+            Some(ms) => {
+                let (season, episode) = match (section, ms.season) {
+                    (None, 0) => (0, ms.specials + number),
+                    (None, season) => (season, number),
+                    // the extras of a movie come after its main episodes
+                    (Some(_), 0) => (0, ms.specials + self.episodes.len() as u32 + number),
+                    (Some(_), _) => (0, ms.specials + number),
+                };
+                let show = ms.show.show_name();
+                let title = media_server_file_name(show, season, episode, &ep.long_title);
+                let subdir = PathBuf::from(format!("Season {:02}", season));
+                let mut task = Task::new(ep.link.clone(), title, number as usize, key);
+                task.sidecars = self.sidecars(ms, &subdir, &task.title, season, episode, ep);
+                task.subdir = Some(subdir);
+                task
+            }
+            None => {
+                let ep_num = match section {
+                    Some(s) => section_ep_num(&s.title, &ep.ep_num),
+                    None => ep.ep_num.clone(),
+                };
+                let title = get_bangumi_file_name(&self.title, &ep_num, &ep.long_title);
+                let mut task = Task::new(ep.link.clone(), title, number as usize, key);
+                task.subdir = layout.season.map(PathBuf::from);
+                task
+            }
+        };
+        task.details = Details {
+            group: match (layout.season, section) {
+                (Some(season), Some(s)) => Some(format!("{} {}", season, s.title)),
+                (Some(season), None) => Some(season.to_owned()),
+                (None, s) => s.map(|s| s.title.clone()),
+            },
+            ..ep.details(section.is_some())
+        };
+        task.op_ed = ep.skip.op_ed();
+        task
+    }
+
+    /// the NFO files and images of an episode, its season and the show
+    fn sidecars(
+        &self,
+        ms: MediaServer,
+        subdir: &Path,
+        file_name: &str,
+        season: u32,
+        episode: u32,
+        ep: &Episode,
+    ) -> Vec<Sidecar> {
+        let show = ms.show;
+        let title = if ep.long_title.is_empty() {
+            &ep.ep_num
+        } else {
+            &ep.long_title
+        };
+        let aired = DateTime::from_timestamp(ep.pub_time, 0)
+            .filter(|_| ep.pub_time > 0)
+            .map(|t| t.format("%Y-%m-%d").to_string());
+        let mut sidecars = vec![
+            Sidecar::Text {
+                path: subdir.join(file_name).with_extension("nfo"),
+                content: nfo::episode(title, show.show_name(), season, episode, aired.as_deref()),
+            },
+            Sidecar::Text {
+                path: PathBuf::from("tvshow.nfo"),
+                content: nfo::tvshow(show.show_name(), &show.evaluate, show.premiered()),
+            },
+        ];
+        sidecars.extend(image("poster.jpg", &show.cover));
+        sidecars.extend(image("fanart.jpg", &show.bkg_cover));
+        if season > 0 {
+            sidecars.push(Sidecar::Text {
+                path: subdir.join("season.nfo"),
+                content: nfo::season(&self.season_title, season, &self.evaluate),
+            });
+            sidecars.extend(image(subdir.join("poster.jpg"), &self.cover));
+        }
+        sidecars
+    }
+
+    /// the date the season started airing, like `2024-01-01`
+    fn premiered(&self) -> Option<&str> {
+        let time = &self.publish.as_ref()?.pub_time;
+        time.get(..10).filter(|d| !d.is_empty())
+    }
+
+    /// the tasks of the main episodes followed by the extras with one of the `access` types,
+    fn tasks(&self, layout: Layout, access: &[Access]) -> Vec<Task> {
+        let (included, excluded): (Vec<_>, Vec<_>) = self
+            .all_episodes()
+            .partition(|(s, ep, _)| access.contains(&ep.access(s.is_some())));
+
+        // tell the user which episodes are left out and how to get them
+        let mut counts: Vec<(Access, usize)> = Vec::new();
+        for (s, ep, _) in excluded.iter() {
+            let a = ep.access(s.is_some());
+            match counts.iter_mut().find(|(k, _)| *k == a) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((a, 1)),
+            }
+        }
+        if !counts.is_empty() {
+            let counts: Vec<String> = counts
+                .iter()
+                .map(|(a, n)| format!("{} 个{}", n, a.label()))
+                .collect();
+            info!(
+                "《{}》已跳过 {}，可以通过 --access 包含",
+                self.title,
+                counts.join("、")
+            );
+        }
+
+        included
+            .into_iter()
+            .map(|ep| self.task(ep, layout))
+            .collect()
+    }
+}
+
+fn image(path: impl Into<PathBuf>, url: &str) -> Option<Sidecar> {
+    (!url.is_empty()).then(|| Sidecar::Remote {
+        path: path.into(),
+        url: url.to_owned(),
+    })
 }
 
 impl Client {
@@ -44,7 +385,8 @@ impl Client {
             "https://api.bilibili.com/pgc/view/web/season?{}={}",
             id_name, id
         );
-        let mut resp: Value = self.get(&url).send().await?.json().await?;
+        let mut resp: Value = self.send(self.get(&url)).await?.json().await?;
+        check_code(&resp)?;
         let info: BangumiInfo = serde_json::from_value(resp["result"].take())?;
 
         Ok(info)
@@ -53,8 +395,9 @@ impl Client {
     /// fetch bangumi **season_id** via **media_id**
     async fn fetch_bangumi_sid(&self, id: i64) -> Result<i64> {
         let url = format!("https://api.bilibili.com/pgc/review/user?media_id={id}");
-        let mut resp: Value = self.get(&url).send().await?.json().await?;
+        let mut resp: Value = self.send(self.get(&url)).await?.json().await?;
 
+        check_code(&resp)?;
         let sid = resp["result"]["media"]["season_id"]
             .take()
             .as_i64()
@@ -62,7 +405,13 @@ impl Client {
         Ok(sid)
     }
 
-    pub async fn get_bangumi(&self, url: &str, dir: &mut PathBuf) -> Result<Vec<Task>> {
+    /// get the episodes of a bangumi, and those of every related season with `all_seasons`
+    pub async fn get_bangumi(
+        &self,
+        url: &str,
+        dir: &mut PathBuf,
+        options: BangumiOptions,
+    ) -> Result<(Metadata, Vec<Task>)> {
         let info = match bangumi_url_parser(url) {
             Ok(SeasonID(id)) => self.fetch_bangumi_info("season_id", id).await?,
             Ok(MediaID(id)) => {
@@ -71,14 +420,19 @@ impl Client {
             }
             Ok(EpisodeID(id)) => {
                 let info = self.fetch_bangumi_info("ep_id", id).await?;
-                let ep_list = info.episodes;
-                let target = ep_list.into_iter().find(|ep| ep.ep_id == id);
+                let target = info.all_episodes().find(|(_, ep, _)| ep.ep_id == id);
                 if let Some(ep) = target {
-                    return Ok(vec![Task::new(
-                        ep.link,
-                        get_bangumi_file_name(&info.title, &ep.ep_num, &ep.long_title),
-                        ep.ep_num.parse().unwrap(),
-                    )]);
+                    let layout = Layout {
+                        season: None,
+                        media_server: options
+                            .media_server
+                            .then(|| info.media_server(info.season_id)),
+                    };
+                    let task = info.task(ep, layout);
+                    if options.media_server {
+                        dir.push(info.show_name());
+                    }
+                    return Ok((Metadata::Bangumi(info), vec![task]));
                 }
                 return Err("未找到番剧".into());
             }
@@ -86,20 +440,51 @@ impl Client {
                 return Err(e);
             }
         };
-        println!("获取番剧列表成功\n《{}》, 共{}集", &info.title, info.total);
-        dir.push(&info.title);
-        let mut video_list: Vec<Task> = Vec::new();
-        let filtered_ep_list = info.episodes.into_iter().filter(|ep| ep.badge_type != 1);
+        info!("获取番剧列表成功\n《{}》, 共{}集", &info.title, info.total);
+        if !options.all_seasons || info.seasons.len() <= 1 {
+            let layout = Layout {
+                season: None,
+                media_server: options
+                    .media_server
+                    .then(|| info.media_server(info.season_id)),
+            };
+            let video_list = info.tasks(layout, &options.access);
+            dir.push(if options.media_server {
+                info.show_name()
+            } else {
+                &info.title
+            });
+            return Ok((Metadata::Bangumi(info), video_list));
+        }
 
-        // println!("{:#?}", filtered_ep_list);
-        filtered_ep_list.enumerate().for_each(|(i, ep)| {
-            video_list.push(Task::new(
-                ep.link,
-                get_bangumi_file_name(&info.title, &ep.ep_num, &ep.long_title),
-                i,
-            ))
-        });
-        Ok(video_list)
+        // every season is saved in its own folder under the series
+        dir.push(info.show_name());
+        let mut video_list: Vec<Task> = Vec::new();
+        for season in info.seasons.iter() {
+            let fetched;
+            let s_info = if season.season_id == info.season_id {
+                &info
+            } else {
+                // a season that fails to load should not cost the others
+                fetched = match self.fetch_bangumi_info("season_id", season.season_id).await {
+                    Ok(fetched) => fetched,
+                    Err(e) => {
+                        error!("获取《{}》失败，已跳过: {}", season.season_title, e);
+                        continue;
+                    }
+                };
+                info!("《{}》, 共{}集", &fetched.title, fetched.total);
+                &fetched
+            };
+            let layout = Layout {
+                season: Some(&s_info.title),
+                media_server: options
+                    .media_server
+                    .then(|| info.media_server(season.season_id)),
+            };
+            video_list.extend(s_info.tasks(layout, &options.access));
+        }
+        Ok((Metadata::Bangumi(info), video_list))
     }
 }
 
@@ -120,19 +505,192 @@ fn bangumi_url_parser(url: &str) -> Result<BangumiID> {
     Err("解析番剧 id 失败".into())
 }
 
-fn get_bangumi_file_name(b_title: &str, ep_num: &str, ep_title: &str) -> String {
+pub fn get_bangumi_file_name(b_title: &str, ep_num: &str, ep_title: &str) -> String {
     let formatted_ep_num = if let Ok(num) = ep_num.parse::<i32>() {
         format!("{:02}", num)
     } else {
         ep_num.to_string()
     };
+    if ep_title.is_empty() {
+        return format!("{} - {}", b_title, formatted_ep_num);
+    }
     format!("{} - {} [{}]", b_title, formatted_ep_num, ep_title)
 }
 
+/// the file name of an episode in the media server layout, like `Show - S01E03 - Title`
+fn media_server_file_name(show: &str, season: u32, episode: u32, ep_title: &str) -> String {
+    let name = format!("{} - S{:02}E{:02}", show, season, episode);
+    if ep_title.is_empty() {
+        return name;
+    }
+    format!("{} - {}", name, ep_title)
+}
+
+/// the episode number of an extra: numbered ones like `1` become `SP01` or `PV01`,
+/// named ones like `PV` or `OP` are kept
+fn section_ep_num(section: &str, ep_num: &str) -> String {
+    match ep_num.parse::<i32>() {
+        Ok(num) if section.contains("PV") => format!("PV{:02}", num),
+        Ok(num) => format!("SP{:02}", num),
+        Err(_) => ep_num.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod bangumi {
 
+    use super::{Access, Layout};
     use crate::http::client::Client;
+    use serde_json::json;
+
+    fn episode(ep_id: i64, title: &str, long_title: &str) -> serde_json::Value {
+        json!({
+            "ep_id": ep_id, "long_title": long_title, "link": "", "title": title,
+            "badge_type": 0, "status": 2,
+        })
+    }
+
+    #[test]
+    fn sections() {
+        let info: super::BangumiInfo = serde_json::from_value(json!({
+            "title": "番剧", "link": "", "season_id": 1, "media_id": 1,
+            "season_title": "第一季", "total": 2,
+            "episodes": [episode(1, "1", "开始"), episode(2, "2", "")],
+            "section": [
+                {"title": "PV&其他", "episodes": [episode(3, "PV", "预告"), episode(4, "2", "")]},
+                {"title": "特别篇", "episodes": [episode(5, "1", "总集篇")]},
+            ],
+            "seasons": [{"season_id": 1, "season_title": "第一季"}],
+        }))
+        .unwrap();
+
+        let tasks = info.tasks(Layout::default(), &[Access::Free]);
+        let titles: Vec<_> = tasks.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            [
+                "番剧 - 01 [开始]",
+                "番剧 - 02",
+                "番剧 - PV [预告]",
+                "番剧 - PV02",
+                "番剧 - SP01 [总集篇]"
+            ]
+        );
+        assert_eq!(tasks[1].details.group, None);
+        assert_eq!(tasks[2].details.group.as_deref(), Some("PV&其他"));
+
+        // a link to a single episode gives the same task as the full list
+        let ep = info
+            .all_episodes()
+            .find(|(_, ep, _)| ep.ep_id == 4)
+            .unwrap();
+        let single = info.task(ep, Layout::default());
+        assert_eq!(
+            (single.number, &single.key, &single.title),
+            (tasks[3].number, &tasks[3].key, &tasks[3].title)
+        );
+
+        let season = Layout {
+            season: Some("番剧 第一季"),
+            media_server: None,
+        };
+        let tasks = info.tasks(season, &[Access::Free]);
+        assert_eq!(tasks[3].number, 2);
+        assert_eq!(tasks[0].details.group.as_deref(), Some("番剧 第一季"));
+        assert_eq!(
+            tasks[4].details.group.as_deref(),
+            Some("番剧 第一季 特别篇")
+        );
+        assert_eq!(
+            tasks[4].subdir.as_deref(),
+            Some(std::path::Path::new("番剧 第一季"))
+        );
+    }
+
+    #[test]
+    fn access_filter() {
+        let mut member = episode(2, "2", "");
+        member["status"] = json!(13);
+        let mut preview = episode(3, "3", "");
+        preview["badge_type"] = json!(1);
+        let mut pv = episode(4, "PV", "");
+        pv["badge_type"] = json!(1);
+        let info: super::BangumiInfo = serde_json::from_value(json!({
+            "title": "番剧", "link": "", "season_id": 1, "media_id": 1,
+            "season_title": "第一季", "total": 3,
+            "episodes": [episode(1, "1", ""), member, preview],
+            "section": [{"title": "PV&其他", "episodes": [pv]}],
+        }))
+        .unwrap();
+
+        let access = super::BangumiOptions::default().access;
+        let tasks = info.tasks(Layout::default(), &access);
+        let keys: Vec<_> = tasks.iter().map(|t| &*t.key).collect();
+        assert_eq!(keys, ["ep1", "ep2", "ep4"]);
+        assert_eq!(tasks[1].details.access, Access::Member);
+        assert_eq!(tasks[2].number, 1);
+
+        let tasks = info.tasks(Layout::default(), &[Access::Preview]);
+        assert_eq!(tasks.len(), 1);
+        // the episode number is kept when earlier episodes are left out
+        assert_eq!(tasks[0].title, "番剧 - 03");
+    }
+
+    #[test]
+    fn media_server_layout() {
+        let info: super::BangumiInfo = serde_json::from_value(json!({
+            "title": "番剧 第二季", "link": "", "season_id": 2, "media_id": 1,
+            "season_title": "第二季", "total": 1, "cover": "https://example.com/a.jpg",
+            "series": {"series_title": "番剧"},
+            "episodes": [episode(1, "1", "开始")],
+            "section": [{"title": "特别篇", "episodes": [episode(2, "1", "")]}],
+            "seasons": [
+                {"season_id": 1, "season_title": "第一季", "season_type": 1},
+                {"season_id": 3, "season_title": "剧场版", "season_type": 2},
+                {"season_id": 2, "season_title": "第二季", "season_type": 1},
+            ],
+        }))
+        .unwrap();
+        // the movie is not counted as a season, its episodes are specials
+        let movie = info.media_server(3);
+        assert_eq!((movie.season, movie.specials), (0, 200));
+        let layout = Layout {
+            season: None,
+            media_server: Some(info.media_server(info.season_id)),
+        };
+        let tasks = info.tasks(layout, &[Access::Free]);
+        assert_eq!(tasks[0].title, "番剧 - S02E01 - 开始");
+        assert_eq!(
+            tasks[0].subdir.as_deref(),
+            Some(std::path::Path::new("Season 02"))
+        );
+        // numbered after the position of the season so that seasons never share a special
+        assert_eq!(tasks[1].title, "番剧 - S00E301");
+        assert_eq!(
+            tasks[1].subdir.as_deref(),
+            Some(std::path::Path::new("Season 00"))
+        );
+
+        let paths: Vec<_> = tasks[0]
+            .sidecars
+            .iter()
+            .map(|s| match s {
+                super::Sidecar::Text { path, .. } | super::Sidecar::Remote { path, .. } => {
+                    path.to_str().unwrap()
+                }
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "Season 02/番剧 - S02E01 - 开始.nfo",
+                "tvshow.nfo",
+                "poster.jpg",
+                "Season 02/season.nfo",
+                "Season 02/poster.jpg"
+            ]
+        );
+    }
 
     #[tokio::test]
     async fn test_bangumi_eps() {
@@ -156,7 +714,10 @@ mod bangumi {
         // let url = "https://www.bilibili.com/bangumi/play/ss47561?spm_id_from=333.999.0.0";
         let url = "https://www.bilibili.com/bangumi/media/md21231728";
         let mut dir = dirs::home_dir().unwrap();
-        let info = client.get_bangumi(url, &mut dir).await.unwrap();
+        let info = client
+            .get_bangumi(url, &mut dir, Default::default())
+            .await
+            .unwrap();
         println!("{:#?}", info);
     }
 }
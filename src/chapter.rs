@@ -0,0 +1,230 @@
+//! 番剧片头片尾的章节和裁剪
+
+/// 片头和片尾的时间范围，单位为秒
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpEd {
+    pub op: Option<(f64, f64)>,
+    pub ed: Option<(f64, f64)>,
+}
+
+/// 合并音视频时写入的章节和去掉的片段
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MergeOptions {
+    /// the chapters on the timeline of the input, see `chapters_after` for the output
+    pub chapters: Vec<Chapter>,
+    /// time ranges in seconds removed from the output, sorted and not overlapping
+    pub cut: Vec<(f64, f64)>,
+    /// 视频的画质代码，写入输出文件供 `--overwrite if-better-quality` 比较
+    pub quality: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    /// 开始和结束时间，单位为秒
+    pub start: f64,
+    pub end: f64,
+    pub title: String,
+}
+
+impl OpEd {
+    /// the chapters of a video of `duration` seconds like `Part A`, `OP`, `Part B`, `ED`,
+    /// with the opening and ending removed if `cut` is set. An unknown duration is 0
+    pub fn merge_options(&self, duration: f64, cut: bool) -> MergeOptions {
+        let mut marks: Vec<((f64, f64), &str)> = [(self.op, "OP"), (self.ed, "ED")]
+            .into_iter()
+            .filter_map(|(range, title)| Some((range?, title)))
+            .filter(|((start, end), _)| start < end && (duration == 0.0 || *start < duration))
+            .collect();
+        if marks.is_empty() {
+            return MergeOptions::default();
+        }
+        marks.sort_by(|a, b| a.0 .0.total_cmp(&b.0 .0));
+
+        // the parts between the marks are named Part A, Part B...
+        let mut segments: Vec<(f64, f64, Option<&str>)> = Vec::new();
+        let mut pos = 0.0;
+        for ((start, end), title) in marks {
+            let start = start.max(pos);
+            if start > pos {
+                segments.push((pos, start, None));
+            }
+            if end > start {
+                segments.push((start, end, Some(title)));
+                pos = end;
+            }
+        }
+        if duration > pos {
+            segments.push((pos, duration, None));
+        }
+
+        let mut options = MergeOptions::default();
+        let mut part = b'A';
+        for (start, end, mark) in segments {
+            if cut && mark.is_some() {
+                options.cut.push((start, end));
+                continue;
+            }
+            let title = match mark {
+                Some(title) => title.to_owned(),
+                None => {
+                    part += 1;
+                    format!("Part {}", (part - 1) as char)
+                }
+            };
+            options.chapters.push(Chapter { start, end, title });
+        }
+        options
+    }
+}
+
+impl MergeOptions {
+    /// the chapters on the timeline of the output, where `cuts` are the ranges the video cut
+    /// actually removed, which end later than `cut` on the next keyframe
+    pub fn chapters_after(&self, cuts: &[(f64, f64)]) -> Vec<Chapter> {
+        let position = |t: f64| {
+            let mut removed = 0.0;
+            for &(start, end) in cuts {
+                if t >= end {
+                    removed += end - start;
+                } else if t > start {
+                    return start - removed;
+                }
+            }
+            t - removed
+        };
+        self.chapters
+            .iter()
+            .map(|c| Chapter {
+                start: position(c.start),
+                end: position(c.end),
+                title: c.title.clone(),
+            })
+            .filter(|c| c.end > c.start)
+            .collect()
+    }
+}
+
+/// 用流复制裁剪视频时决定保留哪些包
+///
+/// 只能从关键帧开始继续输出，所以每段实际去掉的范围会延长到其后的第一个关键帧，
+/// 音频按视频实际去掉的范围裁剪以保持同步
+#[derive(Debug)]
+pub struct Cutter {
+    cuts: Vec<(f64, f64)>,
+    /// the next cut which is not finished
+    next: usize,
+    cutting: bool,
+}
+
+impl Cutter {
+    pub fn new(cuts: Vec<(f64, f64)>) -> Self {
+        Self {
+            cuts,
+            next: 0,
+            cutting: false,
+        }
+    }
+
+    /// for a video packet at `t` seconds in decoding order, the duration in seconds removed
+    /// before it, or `None` if the packet is dropped
+    pub fn video(&mut self, t: f64, key: bool) -> Option<f64> {
+        loop {
+            if self.cutting {
+                if !key || t < self.cuts[self.next].1 {
+                    return None;
+                }
+                self.cuts[self.next].1 = t;
+                self.next += 1;
+                self.cutting = false;
+            }
+            match self.cuts.get(self.next) {
+                Some(&(start, _)) if t >= start => self.cutting = true,
+                _ => return Some(self.removed()),
+            }
+        }
+    }
+
+    /// the duration removed before the current position of the video
+    pub fn removed(&self) -> f64 {
+        self.cuts[..self.next].iter().map(|(s, e)| e - s).sum()
+    }
+
+    /// the ranges actually removed from the video, a cut that is not finished when the video
+    /// ends lasts to the end
+    pub fn finish(mut self) -> Vec<(f64, f64)> {
+        if self.cutting {
+            self.cuts[self.next].1 = f64::INFINITY;
+        }
+        self.cuts
+    }
+}
+
+/// for a packet at `t` seconds, the duration removed before it, or `None` if it is removed
+pub fn shift(cuts: &[(f64, f64)], t: f64) -> Option<f64> {
+    let mut removed = 0.0;
+    for &(start, end) in cuts {
+        if t >= end {
+            removed += end - start;
+        } else if t >= start {
+            return None;
+        }
+    }
+    Some(removed)
+}
+
+#[cfg(test)]
+mod chapter_test {
+    use super::*;
+
+    #[test]
+    fn chapters_and_cut() {
+        let op_ed = OpEd {
+            op: Some((20.0, 110.0)),
+            ed: Some((1330.0, 1420.0)),
+        };
+        let titles = |o: &MergeOptions| -> Vec<String> {
+            o.chapters.iter().map(|c| c.title.clone()).collect()
+        };
+
+        let options = op_ed.merge_options(1440.0, false);
+        assert_eq!(titles(&options), ["Part A", "OP", "Part B", "ED", "Part C"]);
+        assert!(options.cut.is_empty());
+
+        let options = op_ed.merge_options(1440.0, true);
+        assert_eq!(titles(&options), ["Part A", "Part B", "Part C"]);
+        assert_eq!(options.cut, [(20.0, 110.0), (1330.0, 1420.0)]);
+        let chapters = options.chapters_after(&options.cut);
+        assert_eq!(chapters[2].start, 1330.0 - 90.0);
+        assert_eq!(chapters[2].end, 1440.0 - 180.0);
+        // the next keyframes are 2 seconds after the opening and 5 after the ending
+        let chapters = options.chapters_after(&[(20.0, 112.0), (1330.0, 1425.0)]);
+        let times: Vec<_> = chapters.iter().map(|c| (c.start, c.end)).collect();
+        assert_eq!(times, [(0.0, 20.0), (20.0, 1238.0), (1238.0, 1253.0)]);
+        // an ending which lasts to the end of the video removes the last part
+        let chapters = options.chapters_after(&[(20.0, 110.0), (1330.0, f64::INFINITY)]);
+        assert_eq!(chapters.len(), 2);
+
+        assert_eq!(
+            OpEd::default().merge_options(1440.0, true),
+            MergeOptions::default()
+        );
+    }
+
+    #[test]
+    fn cut_on_keyframes() {
+        let mut cutter = Cutter::new(vec![(2.0, 4.0)]);
+        // a keyframe every 3 seconds
+        let kept: Vec<_> = (0..10)
+            .filter_map(|t| cutter.video(t as f64, t % 3 == 0).map(|r| (t, r)))
+            .collect();
+        assert_eq!(
+            kept,
+            [(0, 0.0), (1, 0.0), (6, 4.0), (7, 4.0), (8, 4.0), (9, 4.0)]
+        );
+        let cuts = cutter.finish();
+        assert_eq!(cuts, [(2.0, 6.0)]);
+        assert_eq!(shift(&cuts, 1.5), Some(0.0));
+        assert_eq!(shift(&cuts, 5.0), None);
+        assert_eq!(shift(&cuts, 6.5), Some(4.0));
+    }
+}
@@ -0,0 +1,260 @@
+use crate::bangumi::get_bangumi_file_name;
+use crate::http::download::{Details, Task, TaskKey};
+use crate::http::fetch::Metadata;
+use crate::http::Result;
+use crate::http::{check_code, client::Client, url_regex};
+use crate::parser::PlayInfo;
+use crate::progress::info;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+use std::path::PathBuf;
+use CourseID::*;
+
+enum CourseID {
+    SeasonID(i64),
+    EpisodeID(i64),
+}
+
+/// 课堂课程
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CourseInfo {
+    season_id: i64,
+    /// 课程名称
+    title: String,
+    #[serde(default)]
+    subtitle: String,
+    #[serde(default)]
+    cover: String,
+    episodes: Vec<CourseEpisode>,
+    user_status: Option<UserStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserStatus {
+    /// 1 为已购买
+    #[serde(default)]
+    payed: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CourseEpisode {
+    /// ep_id
+    id: i64,
+    aid: i64,
+    cid: i64,
+    /// 课程中的序号，从 1 开始
+    index: u32,
+    title: String,
+    /// 时长，单位为秒
+    #[serde(default)]
+    duration: u64,
+    /// 发布时间的时间戳
+    #[serde(default)]
+    release_date: i64,
+    /// 1 为可以观看，即试看或已购买
+    status: i32,
+}
+
+impl CourseEpisode {
+    /// the play page, carrying aid and cid so that the play info can be fetched without the course
+    fn link(&self) -> String {
+        format!(
+            "https://www.bilibili.com/cheese/play/ep{}?aid={}&cid={}",
+            self.id, self.aid, self.cid
+        )
+    }
+
+    fn playable(&self) -> bool {
+        self.status == 1
+    }
+}
+
+impl CourseInfo {
+    fn payed(&self) -> bool {
+        self.user_status.as_ref().is_some_and(|s| s.payed == 1)
+    }
+
+    fn task(&self, ep: &CourseEpisode) -> Task {
+        let title = get_bangumi_file_name(&self.title, &ep.index.to_string(), &ep.title);
+        let mut task = Task::new(ep.link(), title, ep.index as usize, TaskKey::course(ep.id));
+        task.details = Details {
+            duration: (ep.duration > 0).then_some(ep.duration),
+            pub_time: (ep.release_date > 0).then_some(ep.release_date),
+            ..Default::default()
+        };
+        task
+    }
+
+    /// the tasks of the episodes the user can watch, the others need the course to be bought
+    fn tasks(&self) -> Vec<Task> {
+        let (playable, locked): (Vec<_>, Vec<_>) =
+            self.episodes.iter().partition(|ep| ep.playable());
+        if !locked.is_empty() {
+            info!(
+                "《{}》已跳过 {} 集未购买的课程，购买后可以通过 --cookies 下载",
+                self.title,
+                locked.len()
+            );
+        }
+        playable.into_iter().map(|ep| self.task(ep)).collect()
+    }
+}
+
+impl Client {
+    async fn fetch_course_info(&self, id_name: &str, id: i64) -> Result<CourseInfo> {
+        let url = format!(
+            "https://api.bilibili.com/pugv/view/web/season?{}={}",
+            id_name, id
+        );
+        let mut resp: Value = self.send(self.get(&url)).await?.json().await?;
+        check_code(&resp)?;
+        let info: CourseInfo = serde_json::from_value(resp["data"].take())?;
+        Ok(info)
+    }
+
+    /// Fetch the play info of a course episode from the pugv playurl API.
+    /// The course is only fetched when the link does not carry aid and cid
+    pub async fn fetch_course_play_info(&self, link: &str) -> Result<PlayInfo> {
+        info!("[Fetch] 获取课程播放信息");
+        let ep_id = course_ep_id(link).ok_or("解析课程 id 失败")?;
+        let (aid, cid) = match episode_ids(link) {
+            Some(ids) => ids,
+            None => {
+                let info = self.fetch_course_info("ep_id", ep_id).await?;
+                let ep = info
+                    .episodes
+                    .iter()
+                    .find(|ep| ep.id == ep_id)
+                    .ok_or("未找到课程")?;
+                (ep.aid, ep.cid)
+            }
+        };
+        let url = format!(
+            "https://api.bilibili.com/pugv/player/web/playurl?avid={}&cid={}&ep_id={}&qn=0&fnver=0&fnval=4048&fourk=1",
+            aid, cid, ep_id
+        );
+        let mut resp: Value = self.send(self.get(&url)).await?.json().await?;
+        check_code(&resp)?;
+        let play_info: PlayInfo = serde_json::from_value(resp["data"].take())?;
+        Ok(play_info)
+    }
+
+    /// get the episodes of a course, saved in a folder named after the course
+    pub async fn get_course(&self, url: &str, dir: &mut PathBuf) -> Result<(Metadata, Vec<Task>)> {
+        let (info, tasks) = match course_url_parser(url)? {
+            SeasonID(id) => {
+                let info = self.fetch_course_info("season_id", id).await?;
+                info!(
+                    "获取课程列表成功\n《{}》, 共{}集",
+                    &info.title,
+                    info.episodes.len()
+                );
+                let tasks = info.tasks();
+                (info, tasks)
+            }
+            EpisodeID(id) => {
+                let info = self.fetch_course_info("ep_id", id).await?;
+                let ep = info
+                    .episodes
+                    .iter()
+                    .find(|ep| ep.id == id)
+                    .ok_or("未找到课程")?;
+                if !ep.playable() {
+                    return Err(format!("《{}》未购买，无法下载", info.title).into());
+                }
+                let task = info.task(ep);
+                (info, vec![task])
+            }
+        };
+        if !info.payed() && tasks.is_empty() {
+            return Err(format!("《{}》未购买，没有可以下载的课程", info.title).into());
+        }
+        dir.push(&info.title);
+        Ok((Metadata::Course(info), tasks))
+    }
+}
+
+/// the ep_id of a course episode link like `/cheese/play/ep123`
+pub fn course_ep_id(url: &str) -> Option<i64> {
+    match course_url_parser(url) {
+        Ok(EpisodeID(id)) => Some(id),
+        _ => None,
+    }
+}
+
+/// the aid and cid in the query of an episode link made by `CourseEpisode::link`
+fn episode_ids(url: &str) -> Option<(i64, i64)> {
+    let u = Url::parse(url).ok()?;
+    let id = |name: &str| {
+        u.query_pairs()
+            .find(|(k, _)| k == name)
+            .and_then(|(_, v)| v.parse().ok())
+    };
+    Some((id("aid")?, id("cid")?))
+}
+
+/// 从课程链接匹配 season_id 或 ep_id
+fn course_url_parser(url: &str) -> Result<CourseID> {
+    if !url.contains("/cheese/") {
+        return Err("不是课程链接".into());
+    }
+    if let Some(id) = url_regex(r"/ss(\d+)/", url) {
+        return Ok(SeasonID(id.parse()?));
+    }
+    if let Some(id) = url_regex(r"/ep(\d+)/", url) {
+        return Ok(EpisodeID(id.parse()?));
+    }
+    Err("解析课程 id 失败".into())
+}
+
+#[cfg(test)]
+mod cheese_test {
+    use super::*;
+    use serde_json::json;
+
+    fn episode(id: i64, index: u32, status: i32) -> Value {
+        json!({
+            "id": id, "aid": 100 + id, "cid": 200 + id, "index": index,
+            "title": format!("第{}课", index), "duration": 600, "status": status,
+        })
+    }
+
+    #[test]
+    fn course_tasks() {
+        let info: CourseInfo = serde_json::from_value(json!({
+            "season_id": 1, "title": "课程",
+            "episodes": [episode(11, 1, 1), episode(12, 2, 2), episode(13, 3, 1)],
+            "user_status": {"payed": 0},
+        }))
+        .unwrap();
+        assert!(!info.payed());
+
+        let tasks = info.tasks();
+        let keys: Vec<_> = tasks.iter().map(|t| &*t.key).collect();
+        assert_eq!(keys, ["cheese11", "cheese13"]);
+        assert_eq!(tasks[1].title, "课程 - 03 [第3课]");
+        assert_eq!(tasks[1].number, 3);
+        assert_eq!(
+            tasks[1].link,
+            "https://www.bilibili.com/cheese/play/ep13?aid=113&cid=213"
+        );
+        assert_eq!(course_ep_id(&tasks[1].link), Some(13));
+        assert_eq!(episode_ids(&tasks[1].link), Some((113, 213)));
+        assert_eq!(tasks[0].details.duration, Some(600));
+    }
+
+    #[test]
+    fn course_urls() {
+        let url = "https://www.bilibili.com/cheese/play/ss360?query_from=0";
+        assert!(matches!(course_url_parser(url), Ok(SeasonID(360))));
+        let url = "https://www.bilibili.com/cheese/play/ep5761";
+        assert!(matches!(course_url_parser(url), Ok(EpisodeID(5761))));
+        assert_eq!(course_ep_id(url), Some(5761));
+        assert_eq!(episode_ids(url), None);
+        assert_eq!(
+            course_ep_id("https://www.bilibili.com/bangumi/play/ep5761"),
+            None
+        );
+    }
+}
@@ -1,14 +1,22 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use reqwest::Url;
 use std::path::PathBuf;
 
+use crate::http::download::{Access, Overwrite};
+use crate::http::limit::{parse_rate, parse_schedule, Schedule};
+use crate::progress::ProgressMode;
+
 #[derive(Parser, Debug)]
 #[command(name = "bili-dl")]
 #[command(version = "1.3.0")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// 视频链接
-    #[arg(value_parser = validate_url)]
-    pub url: Url,
+    #[arg(required = true, value_parser = validate_url)]
+    pub url: Option<Url>,
 
     /// cookies.txt 的路径
     #[arg(long, short)]
@@ -17,6 +25,141 @@ pub struct Cli {
     /// 下载目录，默认为当前目录
     #[arg(long, short, value_parser = set_dir, default_value = "")]
     pub dl_dir: PathBuf,
+
+    /// 下载记录文件的路径，已记录的视频默认不勾选
+    #[arg(long)]
+    pub download_archive: Option<PathBuf>,
+
+    /// 跳过选择界面，下载全部视频
+    #[arg(long, short)]
+    pub yes: bool,
+
+    /// 跳过选择界面，只下载指定序号的视频，如 1-3,7,10-
+    #[arg(long, value_parser = parse_items)]
+    pub items: Option<Items>,
+
+    /// 下载番剧同系列的全部季度，每季保存到单独的文件夹
+    #[arg(long)]
+    pub all_seasons: bool,
+
+    /// 番剧按 Jellyfin、Kodi 等媒体服务器的格式命名，并生成 NFO 和海报
+    #[arg(long)]
+    pub media_server: bool,
+
+    /// 合并时按关键帧去掉番剧的片头和片尾，不重新编码
+    #[arg(long)]
+    pub cut_op_ed: bool,
+
+    /// 番剧使用的配音，语言代码或名称，如 ja、粤语，没有该配音时使用默认音轨
+    #[arg(long, value_name = "LANG")]
+    pub audio_lang: Option<String>,
+
+    /// 将番剧的全部配音作为带语言标签的音轨合并到同一个 MKV 文件
+    #[arg(long)]
+    pub all_dubs: bool,
+
+    /// 直播间未开播时等待开播后开始录制
+    #[arg(long)]
+    pub wait: bool,
+
+    /// 录制直播时同时保存弹幕、礼物和醒目留言，并为每个分段生成 ASS 字幕
+    #[arg(long)]
+    pub danmaku: bool,
+
+    /// 直播录制按大小分段，如 2G、500M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub segment_size: Option<u64>,
+
+    /// 直播录制按时长分段，单位为秒
+    #[arg(long, value_name = "SECS")]
+    pub segment_time: Option<u64>,
+
+    /// 列出的番剧剧集类型，以逗号分隔，其余的会被跳过
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "free,member,region"
+    )]
+    pub access: Vec<Access>,
+
+    /// 保存临时文件的目录，默认为 ~/.cache/bili-dl
+    #[arg(long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+
+    /// 输出文件已存在时的处理方式
+    #[arg(long, value_enum, default_value_t = Overwrite::Never)]
+    pub overwrite: Overwrite,
+
+    /// 优先使用的 CDN 节点，可多次指定
+    #[arg(long, value_name = "HOST")]
+    pub prefer_cdn: Vec<String>,
+
+    /// 将 upos 节点替换为指定的域名，如 upos-sz-mirrorcos.bilivideo.com
+    #[arg(long, value_name = "HOST")]
+    pub cdn_host: Option<String>,
+
+    /// 连接或下载停滞超过该秒数时切换到备用链接
+    #[arg(long, value_name = "SECS", default_value_t = 15)]
+    pub stall_timeout: u64,
+
+    /// 每个音频或视频流同时使用的连接数
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..=32))]
+    pub connections: u16,
+
+    /// 所有下载共享的限速，如 2M、500K，单位为字节每秒
+    #[arg(long, value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+
+    /// 分时段限速，如 01:00-07:00=0,19:00-23:00=1M，速率为 0 时不限速，其余时段使用 --limit-rate
+    #[arg(long, value_parser = parse_schedule)]
+    pub limit_schedule: Option<Schedule>,
+
+    /// 代理地址，支持 http、https 和 socks5，如 socks5://127.0.0.1:1080
+    #[arg(long)]
+    pub proxy: Option<String>,
+
+    /// 仅用于 API 请求的代理，优先于 --proxy，可用于解除番剧的地区限制
+    #[arg(long)]
+    pub api_proxy: Option<String>,
+
+    /// 仅用于下载音视频的代理，优先于 --proxy
+    #[arg(long)]
+    pub media_proxy: Option<String>,
+
+    /// 请求或下载失败时的最大重试次数
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+
+    /// 以 JSON 格式输出视频信息和选择的格式，不下载
+    #[arg(long)]
+    pub dump_json: bool,
+
+    /// 下载进度的输出方式
+    #[arg(long, value_enum, default_value_t = ProgressMode::Bar)]
+    pub progress: ProgressMode,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// 删除已退出的 bili-dl 进程残留的临时文件
+    Cleanup {
+        /// 保存临时文件的目录，默认为 ~/.cache/bili-dl
+        #[arg(long, value_name = "DIR")]
+        temp_dir: Option<PathBuf>,
+    },
+}
+
+/// 通过 `--items` 指定的视频序号，从 1 开始
+#[derive(Debug, Clone, PartialEq)]
+pub struct Items(Vec<(usize, Option<usize>)>);
+
+impl Items {
+    pub fn contains(&self, n: usize) -> bool {
+        self.0
+            .iter()
+            .any(|&(start, end)| n >= start && end.is_none_or(|e| n <= e))
+    }
 }
 
 fn validate_url(url: &str) -> Result<Url, String> {
@@ -24,6 +167,54 @@ fn validate_url(url: &str) -> Result<Url, String> {
     Ok(u)
 }
 
+fn parse_size(s: &str) -> Result<u64, String> {
+    match parse_rate(s) {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(format!("无效的大小: {}", s)),
+    }
+}
+
+fn parse_items(items: &str) -> Result<Items, String> {
+    let parse_num = |s: &str| -> Result<usize, String> {
+        match s.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("无效的序号: {}", s)),
+        }
+    };
+
+    let mut ranges = Vec::new();
+    for part in items.split(',').filter(|p| !p.trim().is_empty()) {
+        let range = match part.split_once('-') {
+            Some((start, end)) => {
+                let start = if start.trim().is_empty() {
+                    1
+                } else {
+                    parse_num(start)?
+                };
+                let end = if end.trim().is_empty() {
+                    None
+                } else {
+                    Some(parse_num(end)?)
+                };
+                if end.is_some_and(|e| e < start) {
+                    return Err(format!("无效的范围: {}", part));
+                }
+                (start, end)
+            }
+            None => {
+                let n = parse_num(part)?;
+                (n, Some(n))
+            }
+        };
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        return Err("未指定序号".into());
+    }
+    Ok(Items(ranges))
+}
+
 fn set_dir(dir: &str) -> Result<PathBuf, String> {
     if !dir.is_empty() {
         let d = PathBuf::from(dir);
@@ -58,4 +249,24 @@ mod cli_test {
         ]);
         assert_eq!(cli.unwrap().dl_dir, dirs::download_dir().unwrap());
     }
+
+    #[test]
+    fn items_test() {
+        let items = parse_items("1-3,7,10-").unwrap();
+        let selected: Vec<usize> = (1..=12).filter(|&n| items.contains(n)).collect();
+        assert_eq!(selected, vec![1, 2, 3, 7, 10, 11, 12]);
+
+        assert_eq!(parse_items("-2").unwrap(), Items(vec![(1, Some(2))]));
+        assert!(parse_items("0").is_err());
+        assert!(parse_items("5-2").is_err());
+        assert!(parse_items("a-b").is_err());
+        assert!(parse_items(",").is_err());
+    }
+
+    #[test]
+    fn size_test() {
+        assert_eq!(parse_size("500M").unwrap(), 500 * 1024 * 1024);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("2X").is_err());
+    }
 }
@@ -3,53 +3,311 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use ffmpeg_next::{codec, encoder, format, media};
+use ffmpeg_next::{codec, encoder, format, media, Dictionary, Packet, Rational};
 
+use crate::audio::Tags;
+use crate::chapter::{shift, Cutter, MergeOptions};
 use crate::http::Result;
 
+/// an audio input of `merge` and the tags of its output stream
+#[derive(Debug, Clone, Copy)]
+pub struct AudioTrack<'a> {
+    pub path: &'a Path,
+    /// ISO 639-2 code like `jpn`
+    pub language: Option<&'a str>,
+    /// the name shown by players like `粤语`
+    pub title: Option<&'a str>,
+}
+
 /// merge audio and video
 /// ### Parameters
-/// - a_path: the path to audio
+/// - audio: the audio tracks, the first one is the default
 /// - v_path: the path to video
-/// - o_path: the path to write output video
-pub fn merge(a_path: &Path, v_path: &Path, o_path: &PathBuf) -> Result<()> {
-    if o_path.exists() {
-        fs::remove_file(o_path)?;
+/// - o_path: the path to write output video, a `.mkv` path is written as Matroska and MP4 otherwise
+/// - options: the chapters to write and the ranges to cut out
+///
+/// The output is written to `<o_path>.part` first and renamed to `o_path` on success,
+/// so an interrupted merge never leaves a truncated video at `o_path`
+pub fn merge(
+    audio: &[AudioTrack],
+    v_path: &Path,
+    o_path: &Path,
+    options: &MergeOptions,
+) -> Result<()> {
+    let format = match o_path.extension() {
+        Some(ext) if ext == "mkv" => "matroska",
+        _ => "mp4",
+    };
+    write_part(o_path, |part_path| {
+        merge_to(audio, v_path, part_path, format, options)
+    })
+}
+
+/// copy a song to `o_path` as MP4 audio with the title, artist and album tags,
+/// written to `<o_path>.part` first like `merge`
+pub fn tag_audio(a_path: &Path, o_path: &Path, tags: &Tags) -> Result<()> {
+    write_part(o_path, |part_path| tag_audio_to(a_path, part_path, tags))
+}
+
+/// copy the audio and video streams of a live recording into an MP4 file
+pub fn remux(i_path: &Path, o_path: &Path) -> Result<()> {
+    write_part(o_path, |part_path| remux_to(i_path, part_path))
+}
+
+/// run `write` on the part file of `o_path` and rename it to `o_path` on success
+fn write_part(o_path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let part_path = part_path(o_path);
+    match write(&part_path) {
+        Ok(_) => {
+            fs::rename(&part_path, o_path)?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&part_path);
+            Err(e)
+        }
     }
-    fs::File::create(o_path)?;
+}
+
+fn part_path(o_path: &Path) -> PathBuf {
+    let mut p = o_path.as_os_str().to_owned();
+    p.push(".part");
+    PathBuf::from(p)
+}
+
+/// the prefix of the `comment` tag which keeps the quality id of a merged video
+const QUALITY_TAG: &str = "bili-dl quality ";
+
+/// the height of the video stream in an existing file, and its quality id if it was
+/// written by `merge`
+pub fn probe_video(path: &Path) -> Result<(i32, Option<i32>)> {
+    let ictx = format::input(&path)?;
+    let stream = ictx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or("no video stream")?;
+    let height = unsafe { (*stream.parameters().as_ptr()).height };
+    let quality = ictx
+        .metadata()
+        .get("comment")
+        .and_then(|c| c.strip_prefix(QUALITY_TAG)?.parse().ok());
+    Ok((height, quality))
+}
 
-    let mut ia_ctx = format::input(&a_path)?;
+fn merge_to(
+    audio: &[AudioTrack],
+    v_path: &Path,
+    o_path: &Path,
+    format: &str,
+    options: &MergeOptions,
+) -> Result<()> {
     let mut iv_ctx = format::input(&v_path)?;
-    let mut octx = format::output(o_path)?;
+    let mut ia_ctxs = audio
+        .iter()
+        .map(|track| format::input(&track.path))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let mut octx = format::output_as(o_path, format)?;
 
-    let iv_stream = iv_ctx.streams().best(media::Type::Video).unwrap();
-    let ia_stream = ia_ctx.streams().best(media::Type::Audio).unwrap();
+    let iv_stream = iv_ctx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or("no video stream")?;
+    let mut streams = vec![iv_stream];
+    for ia_ctx in ia_ctxs.iter() {
+        streams.push(
+            ia_ctx
+                .streams()
+                .best(media::Type::Audio)
+                .ok_or("no audio stream")?,
+        );
+    }
 
-    let stream_index = [iv_stream.index(), ia_stream.index()];
-    let stream_time_base = [iv_stream.time_base(), ia_stream.time_base()];
+    // output stream 0 is the video, stream `i + 1` the audio track `i`
+    let stream_index: Vec<usize> = streams.iter().map(|s| s.index()).collect();
+    let stream_time_base: Vec<Rational> = streams.iter().map(|s| s.time_base()).collect();
 
-    for stream in [iv_stream, ia_stream].iter() {
-        let mut o_stream = octx.add_stream(encoder::find(codec::Id::None)).unwrap();
+    for (i, stream) in streams.iter().enumerate() {
+        let mut o_stream = octx.add_stream(encoder::find(codec::Id::None))?;
         o_stream.set_parameters(stream.parameters());
         unsafe {
             (*o_stream.parameters().as_mut_ptr()).codec_tag = 0;
         }
+        let Some(track) = i.checked_sub(1).map(|i| audio[i]) else {
+            continue;
+        };
+        let mut metadata = Dictionary::new();
+        if let Some(language) = track.language {
+            metadata.set("language", language);
+        }
+        if let Some(title) = track.title {
+            metadata.set("title", title);
+        }
+        o_stream.set_metadata(metadata);
     }
 
-    octx.set_metadata(iv_ctx.metadata().to_owned());
-    octx.write_header().unwrap();
+    // a cut ends on the keyframe after it, which is only known after reading the video,
+    // while the chapters have to be in place before the header is written
+    let cuts = match options.cut.is_empty() {
+        true => Vec::new(),
+        false => video_cuts(v_path, &options.cut)?,
+    };
+    for (i, chapter) in options.chapters_after(&cuts).iter().enumerate() {
+        octx.add_chapter(
+            i as i64,
+            Rational::new(1, 1000),
+            (chapter.start * 1000.0) as i64,
+            (chapter.end * 1000.0) as i64,
+            &chapter.title,
+        )?;
+    }
+
+    let mut metadata = iv_ctx.metadata().to_owned();
+    if let Some(quality) = options.quality {
+        metadata.set("comment", &format!("{}{}", QUALITY_TAG, quality));
+    }
+    octx.set_metadata(metadata);
+    octx.write_header()?;
 
-    for (i, packer_iter) in [iv_ctx.packets(), ia_ctx.packets()].into_iter().enumerate() {
-        for (stream, mut packet) in packer_iter {
-            if stream.index() == stream_index[i] {
-                let ost = octx.stream(i).unwrap();
-                packet.rescale_ts(stream_time_base[i], ost.time_base());
-                packet.set_position(-1);
-                packet.set_stream(i);
-                packet.write_interleaved(&mut octx).unwrap();
+    // the video decides where the cuts end, since it can only resume on a keyframe
+    let mut cutter = Cutter::new(options.cut.clone());
+    for (stream, packet) in iv_ctx.packets() {
+        if stream.index() != stream_index[0] {
+            continue;
+        }
+        let time_base = stream_time_base[0];
+        let removed = match packet.dts().or(packet.pts()) {
+            Some(ts) => cutter.video(ts as f64 * f64::from(time_base), packet.is_key()),
+            None => Some(cutter.removed()),
+        };
+        if let Some(removed) = removed {
+            write_packet(&mut octx, packet, 0, time_base, removed)?;
+        }
+    }
+
+    let cuts = cutter.finish();
+    for (i, ia_ctx) in ia_ctxs.iter_mut().enumerate() {
+        let index = i + 1;
+        for (stream, packet) in ia_ctx.packets() {
+            if stream.index() != stream_index[index] {
+                continue;
+            }
+            let time_base = stream_time_base[index];
+            let removed = match packet.dts().or(packet.pts()) {
+                Some(ts) => shift(&cuts, ts as f64 * f64::from(time_base)),
+                None => Some(0.0),
+            };
+            if let Some(removed) = removed {
+                write_packet(&mut octx, packet, index, time_base, removed)?;
             }
         }
     }
-    octx.write_trailer().unwrap();
+    octx.write_trailer()?;
+    Ok(())
+}
+
+/// the ranges removed from the video for `cut`, each one extended to the next keyframe
+fn video_cuts(v_path: &Path, cut: &[(f64, f64)]) -> Result<Vec<(f64, f64)>> {
+    let mut ictx = format::input(&v_path)?;
+    let stream = ictx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or("no video stream")?;
+    let (index, time_base) = (stream.index(), stream.time_base());
+    let mut cutter = Cutter::new(cut.to_vec());
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != index {
+            continue;
+        }
+        if let Some(ts) = packet.dts().or(packet.pts()) {
+            cutter.video(ts as f64 * f64::from(time_base), packet.is_key());
+        }
+    }
+    Ok(cutter.finish())
+}
+
+fn tag_audio_to(a_path: &Path, o_path: &Path, tags: &Tags) -> Result<()> {
+    let mut ictx = format::input(&a_path)?;
+    let mut octx = format::output_as(o_path, "mp4")?;
+
+    let ia_stream = ictx
+        .streams()
+        .best(media::Type::Audio)
+        .ok_or("no audio stream")?;
+    let (index, time_base) = (ia_stream.index(), ia_stream.time_base());
+    let mut o_stream = octx.add_stream(encoder::find(codec::Id::None))?;
+    o_stream.set_parameters(ia_stream.parameters());
+    unsafe {
+        (*o_stream.parameters().as_mut_ptr()).codec_tag = 0;
+    }
+
+    let mut metadata = Dictionary::new();
+    metadata.set("title", &tags.title);
+    metadata.set("artist", &tags.artist);
+    if let Some(album) = &tags.album {
+        metadata.set("album", album);
+    }
+    octx.set_metadata(metadata);
+    octx.write_header()?;
+
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == index {
+            write_packet(&mut octx, packet, 0, time_base, 0.0)?;
+        }
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
+fn remux_to(i_path: &Path, o_path: &Path) -> Result<()> {
+    let mut ictx = format::input(&i_path)?;
+    let mut octx = format::output_as(o_path, "mp4")?;
+
+    // the output index and time base of every input stream, data streams are left out
+    let mut mapping: Vec<Option<(usize, Rational)>> = vec![None; ictx.nb_streams() as usize];
+    for stream in ictx.streams() {
+        let medium = stream.parameters().medium();
+        if medium != media::Type::Video && medium != media::Type::Audio {
+            continue;
+        }
+        let mut o_stream = octx.add_stream(encoder::find(codec::Id::None))?;
+        o_stream.set_parameters(stream.parameters());
+        unsafe {
+            (*o_stream.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        mapping[stream.index()] = Some((o_stream.index(), stream.time_base()));
+    }
+    if mapping.iter().all(Option::is_none) {
+        return Err("no audio or video stream".into());
+    }
+
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header()?;
+    for (stream, packet) in ictx.packets() {
+        if let Some((index, time_base)) = mapping[stream.index()] {
+            write_packet(&mut octx, packet, index, time_base, 0.0)?;
+        }
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
+/// write a packet to the output stream `index`, moving it `removed` seconds earlier
+fn write_packet(
+    octx: &mut format::context::Output,
+    mut packet: Packet,
+    index: usize,
+    time_base: Rational,
+    removed: f64,
+) -> Result<()> {
+    if removed > 0.0 {
+        let offset = (removed / f64::from(time_base)).round() as i64;
+        packet.set_pts(packet.pts().map(|ts| ts - offset));
+        packet.set_dts(packet.dts().map(|ts| ts - offset));
+    }
+    let ost = octx.stream(index).unwrap();
+    packet.rescale_ts(time_base, ost.time_base());
+    packet.set_position(-1);
+    packet.set_stream(index);
+    packet.write_interleaved(octx)?;
     Ok(())
 }
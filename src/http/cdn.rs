@@ -0,0 +1,117 @@
+use reqwest::Url;
+
+use crate::parser::MediaInfo;
+
+/// CDN 节点的选择规则
+#[derive(Debug, Default, Clone)]
+pub struct CdnConfig {
+    /// 优先使用的节点，靠前的优先级更高
+    pub prefer: Vec<String>,
+    /// 用于替换 upos 节点的域名，原链接作为备用
+    pub host: Option<String>,
+}
+
+impl CdnConfig {
+    /// all download urls of a stream (`base_url` followed by each `backup_url`) in the order to try
+    pub fn candidates(&self, media: &MediaInfo) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+        if let Some(host) = &self.host {
+            urls.extend(media.urls().filter_map(|u| replace_upos_host(u, host)));
+        }
+        urls.extend(media.urls().map(String::from));
+
+        let mut seen = std::collections::HashSet::new();
+        urls.retain(|u| seen.insert(u.clone()));
+
+        // stable sort keeps the original order among hosts with the same priority
+        urls.sort_by_key(|u| {
+            let host = url_host(u);
+            self.prefer
+                .iter()
+                .position(|p| host == p.as_str())
+                .unwrap_or(self.prefer.len())
+        });
+        urls
+    }
+}
+
+fn replace_upos_host(url: &str, host: &str) -> Option<String> {
+    let mut u = Url::parse(url).ok()?;
+    if !u.host_str()?.starts_with("upos-") {
+        return None;
+    }
+    u.set_host(Some(host)).ok()?;
+    Some(u.to_string())
+}
+
+pub fn url_host(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod cdn_test {
+    use super::*;
+    use crate::parser::parser_test::stream;
+    use serde_json::json;
+
+    fn media() -> MediaInfo {
+        let mut media = stream(80, 1000, "avc1.640032", 1080);
+        media["base_url"] =
+            json!("https://upos-sz-estgoss.bilivideo.com/upgcxcode/1.m4s?deadline=1");
+        media["backup_url"] = json!([
+            "https://upos-sz-mirrorcos.bilivideo.com/upgcxcode/1.m4s?deadline=1",
+            "https://cn-gddg-ct-01-01.bilivideo.com/upgcxcode/1.m4s?deadline=1"
+        ]);
+        serde_json::from_value(media).unwrap()
+    }
+
+    fn hosts(urls: &[String]) -> Vec<String> {
+        urls.iter().map(|u| url_host(u)).collect()
+    }
+
+    #[test]
+    fn default_order() {
+        let urls = CdnConfig::default().candidates(&media());
+        assert_eq!(
+            hosts(&urls),
+            vec![
+                "upos-sz-estgoss.bilivideo.com",
+                "upos-sz-mirrorcos.bilivideo.com",
+                "cn-gddg-ct-01-01.bilivideo.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn prefer_host() {
+        let cdn = CdnConfig {
+            prefer: vec!["cn-gddg-ct-01-01.bilivideo.com".into()],
+            host: None,
+        };
+        let urls = cdn.candidates(&media());
+        assert_eq!(hosts(&urls)[0], "cn-gddg-ct-01-01.bilivideo.com");
+        assert_eq!(urls.len(), 3);
+    }
+
+    #[test]
+    fn replace_host() {
+        let cdn = CdnConfig {
+            prefer: vec![],
+            host: Some("upos-sz-mirrorali.bilivideo.com".into()),
+        };
+        let urls = cdn.candidates(&media());
+        assert_eq!(
+            hosts(&urls),
+            vec![
+                "upos-sz-mirrorali.bilivideo.com",
+                "upos-sz-estgoss.bilivideo.com",
+                "upos-sz-mirrorcos.bilivideo.com",
+                "cn-gddg-ct-01-01.bilivideo.com"
+            ]
+        );
+        assert!(urls[0].ends_with("/upgcxcode/1.m4s?deadline=1"));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::progress::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// 已下载的字节范围 `[start, end)`，按起点排序且互不相邻
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ranges(Vec<(u64, u64)>);
+
+impl Ranges {
+    pub fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        // the first range that touches or follows `start`
+        let i = self.0.partition_point(|r| r.1 < start);
+        let mut j = i;
+        while j < self.0.len() && self.0[j].0 <= end {
+            start = start.min(self.0[j].0);
+            end = end.max(self.0[j].1);
+            j += 1;
+        }
+        self.0.splice(i..j, [(start, end)]);
+    }
+
+    /// the number of downloaded bytes
+    pub fn bytes(&self) -> u64 {
+        self.0.iter().map(|(s, e)| e - s).sum()
+    }
+
+    /// the ranges of `[0, total)` that are not downloaded yet
+    pub fn missing(&self, total: u64) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+        let mut pos = 0;
+        for &(start, end) in self.0.iter() {
+            if start > pos {
+                missing.push((pos, start.min(total)));
+            }
+            pos = pos.max(end);
+        }
+        if pos < total {
+            missing.push((pos, total));
+        }
+        missing.retain(|(s, e)| s < e);
+        missing
+    }
+}
+
+/// 下载进度，保存在音视频临时文件旁的 `<文件名>.json` 中，用于中断后继续下载
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub total: u64,
+    pub done: Ranges,
+}
+
+impl Checkpoint {
+    /// the checkpoint file of a media file
+    pub fn path(media: &Path) -> PathBuf {
+        let mut p = media.as_os_str().to_owned();
+        p.push(".json");
+        PathBuf::from(p)
+    }
+
+    /// load the checkpoint of `media`, ignoring it if the media file does not match
+    pub fn load(media: &Path) -> Option<Self> {
+        let s = fs::read_to_string(Self::path(media)).ok()?;
+        let checkpoint: Self = serde_json::from_str(&s).ok()?;
+        let len = fs::metadata(media).ok()?.len();
+        (len == checkpoint.total).then_some(checkpoint)
+    }
+
+    pub fn save(&self, media: &Path) -> io::Result<()> {
+        let path = Self::path(media);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn remove(media: &Path) {
+        let path = Self::path(media);
+        if path.exists() {
+            if let Err(e) = fs::remove_file(path) {
+                error!("Failed to delete file: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod checkpoint_test {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let mut r = Ranges::default();
+        r.insert(10, 20);
+        r.insert(30, 40);
+        r.insert(0, 5);
+        assert_eq!(r.missing(50), vec![(5, 10), (20, 30), (40, 50)]);
+        r.insert(20, 30);
+        assert_eq!(r, Ranges(vec![(0, 5), (10, 40)]));
+        r.insert(3, 12);
+        assert_eq!(r, Ranges(vec![(0, 40)]));
+        assert_eq!(r.bytes(), 40);
+        assert_eq!(r.missing(40), vec![]);
+        r.insert(45, 45);
+        assert_eq!(r.missing(50), vec![(40, 50)]);
+    }
+}
@@ -1,31 +1,54 @@
 use reqwest::{
     cookie::Jar,
     header::{HeaderMap, USER_AGENT},
-    Url,
+    Proxy, RequestBuilder, Response, StatusCode, Url,
 };
 
 use std::{fs, sync::Arc};
 
-const UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/127.0.0.0 Safari/537.36 Edg/127.0.0.0";
+use super::retry::RetryPolicy;
+
+pub const UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/127.0.0.0 Safari/537.36 Edg/127.0.0.0";
 
 #[derive(Debug)]
 pub struct Client {
+    /// client for API and page requests
     cli: reqwest::Client,
+    /// client for CDN downloads
+    media: reqwest::Client,
     cookies: Arc<Jar>,
+    retry: RetryPolicy,
 }
 
 impl Client {
     pub fn new() -> Self {
         let cookies = Arc::new(Jar::default());
-        let mut header = HeaderMap::new();
-        header.insert(USER_AGENT, UA.parse().unwrap());
+        Self {
+            cli: build_client(&cookies, None).unwrap(),
+            media: build_client(&cookies, None).unwrap(),
+            cookies,
+            retry: RetryPolicy::default(),
+        }
+    }
 
-        let cli = reqwest::Client::builder()
-            .cookie_provider(cookies.clone())
-            .default_headers(header)
-            .build()
-            .unwrap();
-        Self { cli, cookies }
+    /// `api_proxy` is used for API and page requests, `media_proxy` for CDN downloads.
+    /// Both accept http, https and socks5 proxies
+    pub fn set_proxy(
+        &mut self,
+        api_proxy: Option<&str>,
+        media_proxy: Option<&str>,
+    ) -> reqwest::Result<()> {
+        self.cli = build_client(&self.cookies, api_proxy)?;
+        self.media = build_client(&self.cookies, media_proxy)?;
+        Ok(())
+    }
+
+    pub fn set_retry(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
     }
 
     pub fn add_cookies(&self, c_path: &str) {
@@ -45,6 +68,48 @@ impl Client {
     pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
         self.cli.get(url)
     }
+
+    /// GET request to a media CDN
+    pub fn get_media(&self, url: &str) -> reqwest::RequestBuilder {
+        self.media.get(url)
+    }
+
+    /// send a request, retrying timeouts, connection errors and 5xx/429 responses
+    pub async fn send(&self, req: RequestBuilder) -> reqwest::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let res = req
+                .try_clone()
+                .expect("request body is not cloneable")
+                .send()
+                .await;
+            let retryable = match &res {
+                Ok(resp) => {
+                    resp.status().is_server_error()
+                        || resp.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+            };
+            if !retryable || attempt >= self.retry.retries {
+                return res;
+            }
+            attempt += 1;
+            tokio::time::sleep(self.retry.delay(attempt)).await;
+        }
+    }
+}
+
+fn build_client(cookies: &Arc<Jar>, proxy: Option<&str>) -> reqwest::Result<reqwest::Client> {
+    let mut header = HeaderMap::new();
+    header.insert(USER_AGENT, UA.parse().unwrap());
+
+    let mut builder = reqwest::Client::builder()
+        .cookie_provider(cookies.clone())
+        .default_headers(header);
+    if let Some(p) = proxy {
+        builder = builder.proxy(Proxy::all(p)?);
+    }
+    builder.build()
 }
 
 #[cfg(test)]
@@ -1,23 +1,71 @@
-use crate::ffmpeg::merge;
-use crate::parser::{
-    choose_audio_stream, choose_video_stream, extract_filename, extract_play_info, MediaInfo,
-};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::header::REFERER;
+use crate::archive::Archive;
+use crate::audio::Tags;
+use crate::chapter::OpEd;
+use crate::ffmpeg::{merge, probe_video, tag_audio, AudioTrack};
+use crate::parser::{AudioStream, DubChoice, FormatChoice, MediaInfo};
+use crate::progress::{self, error, info, TaskProgress};
+use crate::workdir::WorkDir;
+use clap::ValueEnum;
+use futures_util::future::try_join_all;
+use serde::Serialize;
+use std::future::Future;
+use std::ops::Deref;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{error::Error, fs, path::PathBuf};
-use tokio::{self, fs::File, io::AsyncWriteExt};
+use tokio::{self, sync::Notify};
+use tokio_util::sync::CancellationToken;
 
+use super::cdn::CdnConfig;
+use super::checkpoint::Checkpoint;
 use super::client::Client;
+use super::limit::RateLimiter;
+use super::stream::{Cancelled, MediaStream};
 // use super::Result;
 
 type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
 #[derive(Debug)]
 pub struct InputPath {
-    pub v_path: Arc<PathBuf>,
+    /// 歌曲没有视频
+    pub v_path: Option<Arc<PathBuf>>,
     pub a_path: Arc<PathBuf>,
+    /// 其他配音的媒体名和路径，如 `audio.yue`
+    pub dub_paths: Vec<(String, Arc<PathBuf>)>,
+}
+
+/// 视频的唯一标识，番剧为 `ep{ep_id}`，课程为 `cheese{ep_id}`，歌曲为 `au{id}`，普通视频为 `{bvid}_{cid}`
+///
+/// 用作下载记录、临时文件和锁文件的名字，与视频在列表中的位置和选择方式无关
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
+pub struct TaskKey(String);
+
+impl TaskKey {
+    pub fn episode(ep_id: i64) -> Self {
+        Self(format!("ep{}", ep_id))
+    }
+
+    pub fn course(ep_id: i64) -> Self {
+        Self(format!("cheese{}", ep_id))
+    }
+
+    pub fn song(id: i64) -> Self {
+        Self(format!("au{}", id))
+    }
+
+    pub fn video(bvid: &str, cid: i64) -> Self {
+        Self(format!("{}_{}", bvid, cid))
+    }
+}
+
+impl Deref for TaskKey {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
 }
 
 #[derive(Debug)]
@@ -25,193 +73,1095 @@ pub struct Task {
     pub link: String,
     pub title: String,
     pub input_path: Mutex<Option<InputPath>>,
-    pub id: usize,
-    progress: MultiProgress,
+    /// 番剧在正片或 PV、特别篇等分组中的集数，普通视频为 1
+    pub number: usize,
+    pub key: TaskKey,
+    /// 是否已存在于下载记录中
+    pub archived: bool,
+    pub details: Details,
+    /// 保存到下载目录下的子文件夹，如多季番剧的季度
+    pub subdir: Option<PathBuf>,
+    pub sidecars: Vec<Sidecar>,
+    /// 番剧的片头和片尾，合并时写为章节
+    pub op_ed: OpEd,
+    /// 音频区的歌曲只下载音频并写入标签，`None` 为视频
+    pub song: Option<Tags>,
+    pub progress: TaskProgress,
+    status: Mutex<TaskStatus>,
+    /// 在选择界面中为这个视频选择的格式
+    format: Mutex<FormatChoice>,
+}
+
+/// 选择界面中显示的视频信息
+#[derive(Debug, Clone, Default)]
+pub struct Details {
+    /// 时长，单位为秒
+    pub duration: Option<u64>,
+    /// 发布时间的时间戳
+    pub pub_time: Option<i64>,
+    /// 会员、预告等标记
+    pub badge: Option<String>,
+    /// 所属的分组，如 `PV&其他`，`None` 为正片
+    pub group: Option<String>,
+    pub access: Access,
+}
+
+/// 视频的观看权限
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Access {
+    /// 免费观看
+    #[default]
+    Free,
+    /// 需要大会员或付费
+    Member,
+    /// 预告
+    Preview,
+    /// 仅限部分地区观看
+    Region,
+}
+
+impl Access {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Access::Free => "免费",
+            Access::Member => "会员",
+            Access::Preview => "预告",
+            Access::Region => "地区限制",
+        }
+    }
+}
+
+/// 与视频一起保存的附加文件，路径相对于下载目录，已存在时不会覆盖
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sidecar {
+    /// 文本文件，如 NFO
+    Text { path: PathBuf, content: String },
+    /// 从链接下载的文件，如海报和歌词
+    Remote { path: PathBuf, url: String },
+}
+
+/// 任务在下载队列中的状态
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TaskState {
+    #[default]
+    Queued,
+    Downloading,
+    Merging,
+    /// 暂停或被中断，已保存下载进度
+    Paused,
+    Finished,
+    Skipped,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug, Default)]
+struct TaskStatus {
+    state: TaskState,
+    /// 选择的视频和音频格式
+    quality: Option<String>,
+    /// stops the running download of this task only
+    stop: Option<CancellationToken>,
 }
 
 #[derive(Debug)]
 pub struct DownloadTask {
     /// The path to save the video
     pub dir: PathBuf,
-    pub client: Client,
+    /// 保存临时音视频文件的目录
+    pub work_dir: WorkDir,
+    pub client: Arc<Client>,
     pub tasks: Vec<Task>,
+    pub options: DownloadOptions,
+    /// 全部任务结束后继续等待重试，直到取消。用于下载面板
+    pub keep_alive: bool,
+    /// the download order as indexes into `tasks`
+    order: Mutex<Vec<usize>>,
+    /// notified whenever a task is queued again
+    changed: Notify,
+}
+
+#[derive(Debug)]
+pub struct DownloadOptions {
+    pub archive: Option<Archive>,
+    pub cdn: CdnConfig,
+    /// 连接或读取超过该时间没有响应时切换到备用链接
+    pub stall_timeout: Duration,
+    /// 每个音频或视频流同时使用的连接数
+    pub connections: usize,
+    /// 所有下载共享的限速器
+    pub limiter: Option<RateLimiter>,
+    pub overwrite: Overwrite,
+    /// 合并时去掉番剧的片头和片尾
+    pub cut_op_ed: bool,
+    /// 番剧配音的选择
+    pub dub: DubChoice,
+}
+
+/// 输出文件已存在时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Overwrite {
+    /// 跳过下载
+    Never,
+    /// 重新下载并覆盖
+    Always,
+    /// 仅当将要下载的画质更高时覆盖
+    IfBetterQuality,
+}
+
+/// 一次运行中各个任务的结果
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub finished: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// 被取消并保存了进度的任务
+    pub interrupted: usize,
+    /// 取消后没有开始的任务
+    pub remaining: usize,
+    /// 在下载面板中取消的任务
+    pub cancelled: usize,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            archive: None,
+            cdn: CdnConfig::default(),
+            stall_timeout: Duration::from_secs(15),
+            connections: 4,
+            limiter: None,
+            overwrite: Overwrite::Never,
+            cut_op_ed: false,
+            dub: DubChoice::default(),
+        }
+    }
 }
 
 impl Task {
-    pub fn new(link: String, title: String, id: usize) -> Self {
+    pub fn new(link: String, title: String, number: usize, key: TaskKey) -> Self {
         Self {
             link,
             title,
             input_path: Mutex::new(None),
-            id,
-            progress: MultiProgress::new(),
+            number,
+            archived: false,
+            details: Details::default(),
+            subdir: None,
+            sidecars: Vec::new(),
+            op_ed: OpEd::default(),
+            song: None,
+            progress: TaskProgress::new(&key),
+            key,
+            status: Mutex::new(TaskStatus::default()),
+            format: Mutex::new(FormatChoice::default()),
         }
     }
 
-    fn set_input_path(&self, v_path: PathBuf, a_path: PathBuf) {
+    pub fn format(&self) -> FormatChoice {
+        self.format.lock().unwrap().clone()
+    }
+
+    pub fn set_format(&self, choice: FormatChoice) {
+        *self.format.lock().unwrap() = choice;
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.status.lock().unwrap().state.clone()
+    }
+
+    pub fn quality(&self) -> Option<String> {
+        self.status.lock().unwrap().quality.clone()
+    }
+
+    fn set_state(&self, state: TaskState) {
+        self.status.lock().unwrap().state = state;
+    }
+
+    fn set_input_path(
+        &self,
+        v_path: Option<PathBuf>,
+        a_path: PathBuf,
+        dub_paths: Vec<(String, PathBuf)>,
+    ) {
         let mut input_path = self.input_path.lock().unwrap();
         *input_path = Some(InputPath {
-            v_path: Arc::new(v_path),
+            v_path: v_path.map(Arc::new),
             a_path: Arc::new(a_path),
+            dub_paths: dub_paths
+                .into_iter()
+                .map(|(media, path)| (media, Arc::new(path)))
+                .collect(),
         });
     }
 
     fn get_media_path(&self, media: &str) -> Arc<PathBuf> {
         let input_path = self.input_path.lock().unwrap();
-        if media == "video" {
-            input_path.as_ref().unwrap().v_path.clone()
-        } else {
-            input_path.as_ref().unwrap().a_path.clone()
+        let input_path = input_path.as_ref().unwrap();
+        match media {
+            "video" => input_path.v_path.clone().unwrap(),
+            "audio" => input_path.a_path.clone(),
+            _ => input_path
+                .dub_paths
+                .iter()
+                .find(|(m, _)| m == media)
+                .map(|(_, path)| path.clone())
+                .unwrap(),
         }
     }
 
     pub fn remove_media_file(&self) {
         let input_path = self.input_path.lock().unwrap();
-        let v_path = input_path.as_ref().unwrap().v_path.clone();
-        let a_path = input_path.as_ref().unwrap().a_path.clone();
-        if v_path.exists() {
-            if let Err(e) = fs::remove_file(v_path.as_ref()) {
-                eprintln!("Failed to delete file: {}", e);
-            }
-        }
-        if a_path.exists() {
-            if let Err(e) = fs::remove_file(a_path.as_ref()) {
-                eprintln!("Failed to delete file: {}", e);
+        // the task never started downloading
+        let Some(input_path) = input_path.as_ref() else {
+            return;
+        };
+        let dub_paths = input_path.dub_paths.iter().map(|(_, path)| path);
+        for path in input_path
+            .v_path
+            .iter()
+            .chain([&input_path.a_path])
+            .chain(dub_paths)
+        {
+            if path.exists() {
+                if let Err(e) = fs::remove_file(path.as_ref()) {
+                    error!("Failed to delete file: {}", e);
+                }
             }
+            Checkpoint::remove(path);
         }
     }
 
-    async fn download(&self, client: &Client, url: &str, media: &str) -> Result<()> {
-        let resp = client.get(url).header(REFERER, &self.link).send().await?;
-        self.write_chunk(resp, media).await?;
-        Ok(())
+    /// download a media stream into its temporary file
+    async fn download<F, Fut>(
+        &self,
+        client: &Client,
+        options: &DownloadOptions,
+        urls: Vec<String>,
+        media: &str,
+        refresh: F,
+        token: &CancellationToken,
+    ) -> Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Vec<String>>>,
+    {
+        let stream = MediaStream::new(client, &self.link, media, options, urls, refresh);
+        stream
+            .download_to(self.get_media_path(media).as_ref(), &self.progress, token)
+            .await
     }
+}
 
-    async fn write_chunk(&self, mut resp: reqwest::Response, media: &str) -> Result<()> {
-        let status = resp.status();
-        if status != reqwest::StatusCode::OK {
-            return Err(format!("download failed with status: {}", status).into());
+impl DownloadTask {
+    pub fn new(
+        dir: PathBuf,
+        work_dir: WorkDir,
+        client: Arc<Client>,
+        tasks: Vec<Task>,
+        options: DownloadOptions,
+    ) -> Self {
+        Self {
+            dir,
+            work_dir,
+            client,
+            order: Mutex::new((0..tasks.len()).collect()),
+            tasks,
+            options,
+            keep_alive: false,
+            changed: Notify::new(),
         }
+    }
 
-        let mut file = File::create(self.get_media_path(media).as_ref()).await?;
+    /// the download order as indexes into `tasks`
+    pub fn order(&self) -> Vec<usize> {
+        self.order.lock().unwrap().clone()
+    }
 
-        let total_size = resp
-            .content_length()
-            .ok_or("Failed to get content length")?;
-        let pb = self.progress.add(ProgressBar::new(total_size));
-        pb.set_message(format!("downloading {media}"));
-        pb.set_style(
-            ProgressStyle::with_template(r#"{spinner:.green} [{msg}] [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})"#)
-            .unwrap()
-            .progress_chars("#>-")
-        );
-        while let Some(chunk) = resp.chunk().await? {
-            file.write_all(&chunk).await?;
-            pb.inc(chunk.len().try_into().unwrap());
+    /// move the task at `pos` of the queue by `offset`
+    pub fn move_task(&self, pos: usize, offset: isize) {
+        let mut order = self.order.lock().unwrap();
+        if let Some(target) = pos.checked_add_signed(offset).filter(|&t| t < order.len()) {
+            order.swap(pos, target);
+        }
+    }
+
+    /// pause a waiting or downloading task, the downloaded part is kept
+    pub fn pause(&self, task: &Task) {
+        let mut status = task.status.lock().unwrap();
+        match status.state {
+            TaskState::Queued => status.state = TaskState::Paused,
+            TaskState::Downloading => {
+                status.state = TaskState::Paused;
+                if let Some(stop) = &status.stop {
+                    stop.cancel();
+                }
+            }
+            _ => {}
         }
+    }
 
-        pb.finish_with_message("✓");
-        Ok(())
+    /// queue a paused, failed or cancelled task again
+    pub fn resume(&self, task: &Task) {
+        let mut status = task.status.lock().unwrap();
+        if matches!(
+            status.state,
+            TaskState::Paused | TaskState::Failed(_) | TaskState::Cancelled
+        ) {
+            status.state = TaskState::Queued;
+            self.changed.notify_waiters();
+        }
     }
-}
 
-impl DownloadTask {
-    pub fn new(dir: PathBuf, client: Client, tasks: Vec<Task>) -> Self {
-        Self { dir, client, tasks }
+    /// cancel a single task and drop its downloaded part
+    pub fn cancel(&self, task: &Task) {
+        let mut status = task.status.lock().unwrap();
+        match status.state {
+            TaskState::Queued | TaskState::Paused => {
+                status.state = TaskState::Cancelled;
+                drop(status);
+                task.remove_media_file();
+            }
+            TaskState::Downloading => {
+                status.state = TaskState::Cancelled;
+                if let Some(stop) = &status.stop {
+                    stop.cancel();
+                }
+            }
+            _ => {}
+        }
     }
 
-    pub async fn execute(self: Arc<Self>) {
+    /// download the queued tasks one by one until all are done or `token` is cancelled. The
+    /// running task is interrupted on cancellation and its progress is kept for the next run
+    pub async fn execute(self: Arc<Self>, token: CancellationToken) -> Summary {
         self.create_dir_all();
+        loop {
+            let next = tokio::select! {
+                next = self.next_task() => next,
+                _ = token.cancelled() => None,
+            };
+            let Some(task) = next else {
+                break;
+            };
+            let state = self.run_task(task, &token).await;
+            let mut status = task.status.lock().unwrap();
+            status.state = state;
+            status.stop = None;
+        }
+        self.summary()
+    }
+
+    /// wait for the first queued task, `None` when there is nothing left to download
+    async fn next_task(&self) -> Option<&Task> {
+        loop {
+            let changed = self.changed.notified();
+            let mut paused = false;
+            for i in self.order() {
+                let task = &self.tasks[i];
+                match task.state() {
+                    TaskState::Queued => return Some(task),
+                    TaskState::Paused => paused = true,
+                    _ => {}
+                }
+            }
+            if !paused && !self.keep_alive {
+                return None;
+            }
+            changed.await;
+        }
+    }
+
+    /// download a single task and return its final state
+    async fn run_task(&self, task: &Task, token: &CancellationToken) -> TaskState {
+        let stop = token.child_token();
+        {
+            // the task may have been paused or cancelled since it was picked
+            let mut status = task.status.lock().unwrap();
+            if status.state != TaskState::Queued {
+                return status.state.clone();
+            }
+            status.state = TaskState::Downloading;
+            status.stop = Some(stop.clone());
+        }
+        progress::task_started(task);
+        let _lock = match self.work_dir.lock_task(&task.key) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                let message = "另一个 bili-dl 进程正在下载该视频";
+                progress::task_failed(task, message);
+                return TaskState::Failed(message.into());
+            }
+            Err(e) => {
+                let message = format!("创建锁文件失败: {}", e);
+                progress::task_failed(task, &message);
+                return TaskState::Failed(message);
+            }
+        };
+
+        task.progress.reset();
+        match self.download_task(task, &stop).await {
+            Ok(Some(o_path)) => {
+                progress::task_finished(task, &o_path);
+                task.remove_media_file();
+                self.record(task);
+                TaskState::Finished
+            }
+            Ok(None) => TaskState::Skipped,
+            Err(e) => match task.state() {
+                // cancelled from the dashboard
+                TaskState::Cancelled => {
+                    task.remove_media_file();
+                    progress::task_cancelled(task);
+                    TaskState::Cancelled
+                }
+                _ if e.is::<Cancelled>() => {
+                    progress::task_paused(task);
+                    TaskState::Paused
+                }
+                _ => {
+                    progress::task_failed(task, &e.to_string());
+                    TaskState::Failed(e.to_string())
+                }
+            },
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        let mut summary = Summary::default();
         for task in self.tasks.iter() {
-            println!("[Download] 下载视频: {}", task.title);
-            let page_info = self
-                .client
-                .fetch_page_info(&task.link)
-                .await
-                .unwrap_or_else(|_| panic!("获取视频: {} 页面信息失败", task.title));
-            let mut play_info = extract_play_info(page_info).expect("解析播放信息失败");
-            let audio_stream = choose_audio_stream(&mut play_info).unwrap();
-            let video_stream = choose_video_stream(&mut play_info.dash.video).unwrap();
-
-            task.set_input_path(
-                get_file_path(&self.dir, &video_stream, &format!("video{:02}", task.id)),
-                get_file_path(&self.dir, &audio_stream, &format!("audio{:02}", task.id)),
-            );
-
-            let v_part = task.download(&self.client, &video_stream.base_url, "video");
-            let a_part = task.download(&self.client, &audio_stream.base_url, "audio");
-
-            let res = tokio::try_join!(v_part, a_part);
-            match res {
-                Ok((_, _)) => {
-                    let o_path = self.dir.join(&task.title).with_extension("mp4");
-                    // merge audio and video
-                    let merge_status = merge(
-                        task.get_media_path("audio").as_path(),
-                        task.get_media_path("video").as_path(),
-                        &o_path,
-                    );
-
-                    match merge_status {
-                        Ok(_) => {
-                            println!("下载完成: {}\n", o_path.display());
-                            task.remove_media_file();
-                        }
-                        Err(e) => {
-                            panic!("Failed to merge video and audio: {}", e);
-                        }
+            match task.state() {
+                TaskState::Finished => summary.finished += 1,
+                TaskState::Skipped => summary.skipped += 1,
+                TaskState::Failed(_) => summary.failed += 1,
+                TaskState::Paused => summary.interrupted += 1,
+                TaskState::Cancelled => summary.cancelled += 1,
+                _ => summary.remaining += 1,
+            }
+        }
+        summary
+    }
+
+    /// download and merge a single task, returning the path of the output video,
+    /// or `None` if the task is skipped because the output already exists
+    async fn download_task(
+        &self,
+        task: &Task,
+        token: &CancellationToken,
+    ) -> Result<Option<PathBuf>> {
+        if let Some(tags) = &task.song {
+            return self.download_song(task, tags, token).await;
+        }
+        let streams = self
+            .client
+            .fetch_streams(&task.link, &task.format(), &self.options.dub)
+            .await
+            .map_err(|e| format!("获取视频: {} 播放信息失败: {}", task.title, e))?;
+        if let Some(lang) = &self.options.dub.lang {
+            let found = streams
+                .audio
+                .language
+                .as_ref()
+                .is_some_and(|l| l.matches(lang));
+            if !found {
+                info!("{} 没有 {} 配音，使用默认音轨", task.title, lang);
+            }
+        }
+        let video_stream = &streams.video;
+        let audio_stream = &streams.audio.info;
+        // 其他配音需要多音轨，只能写入 MKV
+        let dubs: Vec<(String, &AudioStream)> = streams
+            .dubs
+            .iter()
+            .filter_map(|d| Some((format!("audio.{}", d.language.as_ref()?.lang), d)))
+            .collect();
+
+        let dir = match &task.subdir {
+            Some(subdir) => self.dir.join(subdir),
+            None => self.dir.clone(),
+        };
+        let ext = if dubs.is_empty() { "mp4" } else { "mkv" };
+        let o_path = dir.join(&task.title).with_extension(ext);
+        if let Some((existing, reason)) = self.skip_reason(&o_path, video_stream) {
+            progress::task_skipped(task, &existing, reason);
+            self.write_sidecars(task).await;
+            return Ok(None);
+        }
+        fs::create_dir_all(&dir)?;
+
+        let medias = ["video", "audio"]
+            .into_iter()
+            .chain(dubs.iter().map(|(media, _)| media.as_str()));
+        for media in medias {
+            if self.work_dir.adopt(&task.key, media) {
+                info!("找到 {} 未完成的下载", task.title);
+            }
+        }
+        task.set_input_path(
+            Some(self.work_dir.media_path(&task.key, "video")),
+            self.work_dir.media_path(&task.key, "audio"),
+            dubs.iter()
+                .map(|(media, _)| (media.clone(), self.work_dir.media_path(&task.key, media)))
+                .collect(),
+        );
+        task.status.lock().unwrap().quality = Some(format!(
+            "{} / {}",
+            video_stream.quality(),
+            audio_stream.quality()
+        ));
+
+        let v_urls = self.options.cdn.candidates(video_stream);
+        let a_urls = self.options.cdn.candidates(audio_stream);
+        let v_part = task.download(
+            &self.client,
+            &self.options,
+            v_urls,
+            "video",
+            || self.refresh_urls(task, "video", video_stream.id()),
+            token,
+        );
+        let a_part = task.download(
+            &self.client,
+            &self.options,
+            a_urls,
+            "audio",
+            || self.refresh_urls(task, "audio", audio_stream.id()),
+            token,
+        );
+        let dub_parts = dubs.iter().map(|(media, dub)| {
+            task.download(
+                &self.client,
+                &self.options,
+                self.options.cdn.candidates(&dub.info),
+                media,
+                || self.refresh_urls(task, media, dub.info.id()),
+                token,
+            )
+        });
+        tokio::try_join!(v_part, a_part, try_join_all(dub_parts))?;
+
+        // merge audio and video
+        task.set_state(TaskState::Merging);
+        progress::merge_started(task);
+        let duration = task.details.duration.unwrap_or_default() as f64;
+        let mut paths = vec![task.get_media_path("audio")];
+        paths.extend(dubs.iter().map(|(media, _)| task.get_media_path(media)));
+        let audio: Vec<AudioTrack> = std::iter::once(&streams.audio)
+            .chain(dubs.iter().map(|(_, dub)| *dub))
+            .zip(paths.iter())
+            .map(|(stream, path)| {
+                // 只有一条音轨时不需要标记语言
+                let language = stream.language.as_ref().filter(|_| !dubs.is_empty());
+                AudioTrack {
+                    path,
+                    language: language.map(|l| l.iso639()),
+                    title: language.map(|l| l.title.as_str()),
+                }
+            })
+            .collect();
+        let mut options = task.op_ed.merge_options(duration, self.options.cut_op_ed);
+        options.quality = Some(video_stream.id());
+        merge(
+            &audio,
+            task.get_media_path("video").as_path(),
+            &o_path,
+            &options,
+        )
+        .map_err(|e| format!("Failed to merge video and audio: {}", e))?;
+        self.write_sidecars(task).await;
+        Ok(Some(o_path))
+    }
+
+    /// download a song of the audio area and write its tags, there is no video to merge
+    async fn download_song(
+        &self,
+        task: &Task,
+        tags: &Tags,
+        token: &CancellationToken,
+    ) -> Result<Option<PathBuf>> {
+        let song = self
+            .client
+            .fetch_song_stream(&task.link)
+            .await
+            .map_err(|e| format!("获取歌曲: {} 播放信息失败: {}", task.title, e))?;
+
+        let dir = match &task.subdir {
+            Some(subdir) => self.dir.join(subdir),
+            None => self.dir.clone(),
+        };
+        let o_path = dir.join(format!("{}.m4a", task.title));
+        if o_path.exists() && self.options.overwrite != Overwrite::Always {
+            progress::task_skipped(task, &o_path, "文件已存在");
+            self.write_sidecars(task).await;
+            return Ok(None);
+        }
+        fs::create_dir_all(&dir)?;
+
+        if self.work_dir.adopt(&task.key, "audio") {
+            info!("找到 {} 未完成的下载", task.title);
+        }
+        task.set_input_path(
+            None,
+            self.work_dir.media_path(&task.key, "audio"),
+            Vec::new(),
+        );
+        task.status.lock().unwrap().quality = Some(song.quality);
+        task.download(
+            &self.client,
+            &self.options,
+            song.urls,
+            "audio",
+            || self.refresh_song_urls(task),
+            token,
+        )
+        .await?;
+
+        task.set_state(TaskState::Merging);
+        progress::merge_started(task);
+        tag_audio(task.get_media_path("audio").as_path(), &o_path, tags)
+            .map_err(|e| format!("Failed to write tags: {}", e))?;
+        self.write_sidecars(task).await;
+        Ok(Some(o_path))
+    }
+
+    /// save the sidecar files of a task which do not exist yet, failures are only reported
+    async fn write_sidecars(&self, task: &Task) {
+        for sidecar in task.sidecars.iter() {
+            let (path, res) = match sidecar {
+                Sidecar::Text { path, content } => {
+                    let path = self.dir.join(path);
+                    if path.exists() {
+                        continue;
                     }
+                    let res = write_file(&path, content.as_bytes());
+                    (path, res)
                 }
-                Err(e) => {
-                    eprintln!("下载失败: {}", e);
+                Sidecar::Remote { path, url } => {
+                    let path = self.dir.join(path);
+                    if path.exists() {
+                        continue;
+                    }
+                    let res = match self.fetch_remote(url).await {
+                        Ok(bytes) => write_file(&path, &bytes),
+                        Err(e) => Err(e),
+                    };
+                    (path, res)
                 }
+            };
+            if let Err(e) = res {
+                error!("保存 {} 失败: {}", path.display(), e);
+            }
+        }
+    }
+
+    async fn fetch_remote(&self, url: &str) -> Result<Vec<u8>> {
+        let resp = self.client.send(self.client.get(url)).await?;
+        Ok(resp.error_for_status()?.bytes().await?.to_vec())
+    }
+
+    /// check the overwrite policy against an existing output file, returning the file and why it is kept.
+    /// The video may have been saved as MP4 or, with dubs, as MKV, so both are checked
+    fn skip_reason(
+        &self,
+        o_path: &Path,
+        video_stream: &MediaInfo,
+    ) -> Option<(PathBuf, &'static str)> {
+        ["mp4", "mkv"]
+            .into_iter()
+            .map(|ext| o_path.with_extension(ext))
+            .filter(|path| path.exists())
+            .find_map(|path| {
+                let reason = match self.options.overwrite {
+                    Overwrite::Always => None,
+                    Overwrite::Never => Some("文件已存在"),
+                    Overwrite::IfBetterQuality => match probe_video(&path) {
+                        Ok((height, quality)) if !video_stream.is_better_than(height, quality) => {
+                            Some("已存在相同或更高画质的文件")
+                        }
+                        _ => None,
+                    },
+                };
+                reason.map(|reason| (path, reason))
+            })
+    }
+
+    /// fetch the play info again and return fresh urls of the stream being downloaded
+    async fn refresh_urls(&self, task: &Task, media: &str, id: i32) -> Result<Vec<String>> {
+        let streams = self
+            .client
+            .fetch_streams(&task.link, &task.format(), &self.options.dub)
+            .await
+            .map_err(|e| format!("获取视频: {} 播放信息失败: {}", task.title, e))?;
+        let stream = match media {
+            "video" => Some(streams.video),
+            "audio" => Some(streams.audio.info),
+            _ => streams
+                .dubs
+                .into_iter()
+                .find(|d| {
+                    d.language
+                        .as_ref()
+                        .is_some_and(|l| media == format!("audio.{}", l.lang))
+                })
+                .map(|d| d.info),
+        };
+        let Some(stream) = stream.filter(|s| s.id() == id) else {
+            return Err("重新获取的视频流与正在下载的不一致".into());
+        };
+        Ok(self.options.cdn.candidates(&stream))
+    }
+
+    async fn refresh_song_urls(&self, task: &Task) -> Result<Vec<String>> {
+        let song = self
+            .client
+            .fetch_song_stream(&task.link)
+            .await
+            .map_err(|e| format!("获取歌曲: {} 播放信息失败: {}", task.title, e))?;
+        Ok(song.urls)
+    }
+
+    fn record(&self, task: &Task) {
+        if let Some(archive) = &self.options.archive {
+            if let Err(e) = archive.record(&task.key) {
+                error!("写入下载记录失败: {}", e);
             }
         }
     }
 
+    /// remove the temporary files, keeping unfinished downloads that can be resumed
     pub fn remove_tmp_file(&self) {
-        for task in self.tasks.iter() {
-            task.remove_media_file();
+        match self.work_dir.finish() {
+            Ok(true) => info!(
+                "未完成的下载已保存到 {}，再次下载时会继续",
+                self.work_dir.run_dir().display()
+            ),
+            Ok(false) => {}
+            Err(e) => error!("Failed to delete temporary files: {}", e),
         }
     }
 
     fn create_dir_all(&self) {
         fs::create_dir_all(&self.dir).expect("下载文件夹新建失败");
-        println!("下载路径: {}\t", self.dir.display());
+        info!("下载路径: {}\t", self.dir.display());
     }
 }
 
-fn get_file_path(dir: &Path, media: &MediaInfo, default: &str) -> PathBuf {
-    let filename = extract_filename(&media.base_url, default);
-    dir.join(PathBuf::from(filename))
+/// write a file, creating its parent folders
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod dl_test {
     use super::*;
     use crate::http::client;
+    use crate::http::retry::RetryPolicy;
+    use crate::http::test_server::{serve, temp_dir, Behaviour};
+    use crate::parser::parser_test::stream;
     use dirs;
 
     #[tokio::test]
     async fn dl() {
         let url = "https://www.bilibili.com/video/BV1ub421J7vH";
         let client = client::Client::new();
-        let v = client.get_video(url).await.unwrap();
+        let (_, v) = client.get_video(url).await.unwrap();
 
         let dl = Arc::new(DownloadTask::new(
             dirs::home_dir().unwrap().join("Downloads"),
-            client,
+            WorkDir::create(WorkDir::default_base()).unwrap(),
+            Arc::new(client),
             v,
+            DownloadOptions::default(),
         ));
         println!("{:#?}", dl);
         let cdl = dl.clone();
         let _ = tokio::spawn(async move {
-            cdl.execute().await;
+            cdl.execute(CancellationToken::new()).await;
         })
         .await;
         dl.remove_tmp_file();
     }
 
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    #[test]
+    fn skip_existing_output() {
+        let dir = temp_dir("skip");
+        let mut dl = DownloadTask::new(
+            dir.clone(),
+            WorkDir::create(dir.join("work")).unwrap(),
+            Arc::new(Client::new()),
+            Vec::new(),
+            DownloadOptions::default(),
+        );
+        let video: MediaInfo =
+            serde_json::from_value(stream(80, 1000, "avc1.640032", 1080)).unwrap();
+        let o_path = dir.join("video.mkv");
+        assert_eq!(dl.skip_reason(&o_path, &video), None);
+
+        // a video saved as MP4 before the dubs were chosen is found too
+        fs::write(dir.join("video.mp4"), BODY).unwrap();
+        assert_eq!(
+            dl.skip_reason(&o_path, &video),
+            Some((dir.join("video.mp4"), "文件已存在"))
+        );
+        dl.options.overwrite = Overwrite::Always;
+        assert_eq!(dl.skip_reason(&o_path, &video), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn local_task(name: &str) -> (Task, PathBuf) {
+        let task = Task::new(
+            "https://www.bilibili.com".into(),
+            name.into(),
+            1,
+            TaskKey::video(name, 1),
+        );
+        let path =
+            std::env::temp_dir().join(format!("bili-dl-{}-{}.m4s", name, std::process::id()));
+        task.set_input_path(Some(path.clone()), path.with_extension("audio"), Vec::new());
+        (task, path)
+    }
+
+    async fn no_refresh() -> Result<Vec<String>> {
+        Err("no refresh".into())
+    }
+
+    fn local_client(retries: u32) -> Client {
+        let mut client = Client::new();
+        client.set_retry(RetryPolicy {
+            retries,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+        });
+        client
+    }
+
+    fn local_options(connections: usize) -> DownloadOptions {
+        DownloadOptions {
+            stall_timeout: Duration::from_millis(500),
+            connections,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn paused_before_start() {
+        let dir = temp_dir("paused");
+        let (task, _) = local_task("paused");
+        task.status.lock().unwrap().state = TaskState::Paused;
+        let dl = DownloadTask::new(
+            dir.clone(),
+            WorkDir::create(dir.join("work")).unwrap(),
+            Arc::new(Client::new()),
+            vec![task],
+            DownloadOptions::default(),
+        );
+        // a task paused after it was picked from the queue is not started
+        let state = dl.run_task(&dl.tasks[0], &CancellationToken::new()).await;
+        assert_eq!(state, TaskState::Paused);
+        assert!(dl.tasks[0].status.lock().unwrap().stop.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failover_to_backup_url() {
+        let urls = vec![
+            serve(BODY, Behaviour::Status(404)).await,
+            serve(BODY, Behaviour::Stall).await,
+            serve(BODY, Behaviour::Truncate(10)).await,
+            serve(BODY, Behaviour::Ok).await,
+        ];
+        let (task, path) = local_task("failover");
+        task.download(
+            &local_client(0),
+            &local_options(1),
+            urls,
+            "video",
+            no_refresh,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), BODY);
+        fs::remove_file(&path).unwrap();
+        Checkpoint::remove(&path);
+    }
+
+    #[tokio::test]
+    async fn all_urls_failed() {
+        let urls = vec![
+            serve(BODY, Behaviour::Status(403)).await,
+            serve(BODY, Behaviour::Stall).await,
+        ];
+        let (task, _) = local_task("all-failed");
+        let res = task
+            .download(
+                &local_client(1),
+                &local_options(1),
+                urls,
+                "video",
+                no_refresh,
+                &CancellationToken::new(),
+            )
+            .await;
+        assert!(res.is_err());
+        task.remove_media_file();
+    }
+
+    #[tokio::test]
+    async fn refresh_on_forbidden() {
+        let expired = vec![serve(BODY, Behaviour::Status(403)).await];
+        let fresh = serve(BODY, Behaviour::Ok).await;
+        let (task, path) = local_task("refresh");
+        task.download(
+            &local_client(2),
+            &local_options(1),
+            expired,
+            "video",
+            || {
+                let fresh = fresh.clone();
+                async move { Ok(vec![fresh]) }
+            },
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), BODY);
+        fs::remove_file(&path).unwrap();
+        Checkpoint::remove(&path);
+    }
+
+    #[tokio::test]
+    async fn segmented_download() {
+        let body: Vec<u8> = (0..20u32 << 20).map(|i| (i % 251) as u8).collect();
+        let body: &'static [u8] = Box::leak(body.into_boxed_slice());
+        let urls = vec![
+            serve(body, Behaviour::Truncate(1000)).await,
+            serve(body, Behaviour::Ok).await,
+        ];
+        let (task, path) = local_task("segmented");
+        task.download(
+            &local_client(1),
+            &local_options(4),
+            urls,
+            "video",
+            no_refresh,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(fs::read(&path).unwrap() == body);
+        fs::remove_file(&path).unwrap();
+        Checkpoint::remove(&path);
+    }
+
+    #[tokio::test]
+    async fn resume_from_checkpoint() {
+        let truncated = vec![serve(BODY, Behaviour::Truncate(10)).await];
+        let (task, path) = local_task("resume");
+        let token = CancellationToken::new();
+        let res = task
+            .download(
+                &local_client(0),
+                &local_options(1),
+                truncated,
+                "video",
+                no_refresh,
+                &token,
+            )
+            .await;
+        assert!(res.is_err());
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.done.bytes(), 10);
+
+        let urls = vec![serve(BODY, Behaviour::Ok).await];
+        task.download(
+            &local_client(0),
+            &local_options(1),
+            urls,
+            "video",
+            no_refresh,
+            &token,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), BODY);
+        task.remove_media_file();
+        assert!(!Checkpoint::path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn cancel_download() {
+        let urls = vec![serve(BODY, Behaviour::Stall).await];
+        let (task, path) = local_task("cancel");
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel.cancel();
+        });
+        let mut options = local_options(1);
+        options.stall_timeout = Duration::from_secs(10);
+        let res = task
+            .download(
+                &local_client(0),
+                &options,
+                urls,
+                "video",
+                no_refresh,
+                &token,
+            )
+            .await;
+        assert!(res.unwrap_err().is::<Cancelled>());
+        assert!(Checkpoint::load(&path).is_some());
+        task.remove_media_file();
+    }
+
+    #[tokio::test]
+    async fn queue_management() {
+        let tasks = (1..=3)
+            .map(|i| {
+                Task::new(
+                    String::new(),
+                    format!("t{}", i),
+                    i,
+                    TaskKey::episode(i as i64),
+                )
+            })
+            .collect();
+        let base = temp_dir("queue");
+        let dl = DownloadTask::new(
+            base.clone(),
+            WorkDir::create(base.clone()).unwrap(),
+            Arc::new(Client::new()),
+            tasks,
+            DownloadOptions::default(),
+        );
+
+        dl.move_task(2, -1);
+        dl.move_task(0, -1);
+        assert_eq!(dl.order(), vec![0, 2, 1]);
+
+        dl.pause(&dl.tasks[0]);
+        dl.cancel(&dl.tasks[2]);
+        assert_eq!(dl.tasks[0].state(), TaskState::Paused);
+        assert_eq!(dl.tasks[2].state(), TaskState::Cancelled);
+        assert_eq!(dl.next_task().await.unwrap().key, TaskKey::episode(2));
+
+        dl.resume(&dl.tasks[0]);
+        assert_eq!(dl.next_task().await.unwrap().key, TaskKey::episode(1));
+
+        let summary = dl.summary();
+        assert_eq!((summary.remaining, summary.cancelled), (2, 1));
+        dl.work_dir.finish().unwrap();
+        fs::remove_dir_all(&base).unwrap();
+    }
+
     #[test]
     fn home_dir() {
         let home = dirs::home_dir().unwrap();
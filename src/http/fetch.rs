@@ -1,32 +1,70 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::audio::{is_song, MenuInfo, SongInfo};
+use crate::bangumi::BangumiInfo;
+use crate::cheese::{course_ep_id, CourseInfo};
 use crate::http::client::Client;
 use crate::http::Result;
+use crate::parser::{extract_play_info, DubChoice, FormatChoice, Formats, Streams};
+use crate::progress::info;
 use std::time::Duration;
 
-use super::download::Task;
-use super::url_regex;
+use super::download::{Details, Task, TaskKey};
+use super::{check_code, url_regex};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VideoInfo {
     pub state: i32,
     pub bvid: String,
+    pub cid: i64,
     pub title: String,
     pub desc: String,
     pub duration: i32,
+    /// 发布时间的时间戳
+    #[serde(default)]
+    pub pubdate: i64,
+}
+
+/// 视频或番剧的元数据
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Metadata {
+    Video(VideoInfo),
+    Bangumi(BangumiInfo),
+    Course(CourseInfo),
+    /// 音频区的单曲
+    Song(SongInfo),
+    /// 音频区的歌单
+    Menu(MenuInfo),
+}
+
+/// 登录状态
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Login {
+    pub logged_in: bool,
+    /// 是否为大会员
+    pub vip: bool,
 }
 
 pub enum VideoType {
     Bangumi,
+    /// 课堂的课程
+    Course,
+    /// 音频区的单曲和歌单
+    Audio,
+    /// 直播间，录制而不是下载
+    Live,
     Video,
 }
 
 impl Client {
     /// Fetch the html content of the video playback page
     pub async fn fetch_page_info(&self, url: &str) -> Result<String> {
-        println!("[Fetch] 获取播放页面信息");
-        let resp = self.get(url).timeout(Duration::from_secs(3)).send().await?;
+        info!("[Fetch] 获取播放页面信息");
+        let resp = self
+            .send(self.get(url).timeout(Duration::from_secs(3)))
+            .await?;
         let body = resp.text().await?;
         Ok(body)
     }
@@ -35,14 +73,16 @@ impl Client {
     async fn fetch_video_info(&self, url: &str) -> Result<VideoInfo> {
         if let Some(code) = url_regex(r"/BV(\S+)/", url) {
             let mut resp: Value = self
-                .get(&format!(
-                    "https://api.bilibili.com/x/web-interface/view?bvid=BV{code}"
-                ))
-                .timeout(Duration::from_secs(3))
-                .send()
+                .send(
+                    self.get(&format!(
+                        "https://api.bilibili.com/x/web-interface/view?bvid=BV{code}"
+                    ))
+                    .timeout(Duration::from_secs(3)),
+                )
                 .await?
                 .json()
                 .await?;
+            check_code(&resp)?;
             let info: VideoInfo = serde_json::from_value(resp["data"].take())?;
             Ok(info)
         } else {
@@ -52,30 +92,85 @@ impl Client {
 
     /// Verify login based on cookies
     pub async fn validate_login(&self) -> Result<bool> {
+        Ok(self.login_status().await?.logged_in)
+    }
+
+    /// Check whether the cookies are logged in and have a VIP membership
+    pub async fn login_status(&self) -> Result<Login> {
         let url = "https://api.bilibili.com/x/web-interface/nav";
-        let resp = self.get(url).timeout(Duration::from_secs(3)).send().await?;
+        let resp: Value = self
+            .send(self.get(url).timeout(Duration::from_secs(3)))
+            .await?
+            .json()
+            .await?;
+
+        let login = Login {
+            logged_in: resp["data"]["isLogin"].as_bool().unwrap_or(false),
+            vip: resp["data"]["vipStatus"].as_i64() == Some(1),
+        };
+        match login {
+            Login { vip: true, .. } => info!("登陆成功 (大会员)\n"),
+            Login {
+                logged_in: true, ..
+            } => info!("登陆成功\n"),
+            _ => info!("未登录\n"),
+        }
 
-        // Check if the response contains a login indicator
-        let body = resp.text().await?;
-        let is_logged_in = body.contains("\"isLogin\":true");
+        Ok(login)
+    }
 
-        if is_logged_in {
-            println!("登陆成功\n");
-        } else {
-            println!("未登录\n");
+    /// Fetch the play info of a video and list every stream that can be downloaded
+    pub async fn fetch_formats(&self, link: &str) -> Result<Formats> {
+        if is_song(link) {
+            return Err("歌曲没有可以选择的格式".into());
         }
+        let play_info = match course_ep_id(link) {
+            Some(_) => self.fetch_course_play_info(link).await?,
+            None => extract_play_info(self.fetch_page_info(link).await?)?,
+        };
+        let formats = Formats::new(play_info);
+        if formats.video.is_empty() {
+            return Err("未找到视频流".into());
+        }
+        if formats.audio.is_empty() {
+            return Err("未找到音频流".into());
+        }
+        Ok(formats)
+    }
 
-        Ok(is_logged_in)
+    /// Fetch the play info of a video and choose the streams to download
+    pub async fn fetch_streams(
+        &self,
+        link: &str,
+        choice: &FormatChoice,
+        dub: &DubChoice,
+    ) -> Result<Streams> {
+        let formats = self.fetch_formats(link).await?;
+        Ok(formats.streams(choice, dub).ok_or("未找到视频流")?)
     }
 
-    pub async fn get_video(&self, url: &str) -> Result<Vec<Task>> {
+    pub async fn get_video(&self, url: &str) -> Result<(Metadata, Vec<Task>)> {
         let info = self.fetch_video_info(url).await?;
-        Ok(vec![Task::new(url.to_string(), info.title, 1)])
+        let key = TaskKey::video(&info.bvid, info.cid);
+        let mut task = Task::new(url.to_string(), info.title.clone(), 1, key);
+        task.details = Details {
+            duration: (info.duration > 0).then_some(info.duration as u64),
+            pub_time: (info.pubdate > 0).then_some(info.pubdate),
+            badge: None,
+            ..Default::default()
+        };
+        Ok((Metadata::Video(info), vec![task]))
     }
 }
 
 pub fn process_url(url: &str) -> VideoType {
-    if url.contains("bangumi") {
+    if url.contains("live.bilibili.com") {
+        VideoType::Live
+    } else if url.contains("/cheese/") {
+        VideoType::Course
+    } else if url.contains("/audio/") {
+        VideoType::Audio
+    } else if url.contains("bangumi") {
         VideoType::Bangumi
     } else {
         VideoType::Video
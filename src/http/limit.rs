@@ -0,0 +1,190 @@
+use chrono::{Local, NaiveTime, Timelike};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// 限速的时间段，`start` 和 `end` 为一天中的分钟数，`rate` 为 `None` 时不限速
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    start: u32,
+    end: u32,
+    rate: Option<u64>,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            // crosses midnight, e.g. 23:00-02:00
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// `--limit-schedule` 指定的限速时间表
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule(Vec<Window>);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// 所有下载共享的令牌桶限速器，速率可以随时间表变化
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// 不在时间表中的时段使用的速率
+    default: Option<u64>,
+    schedule: Schedule,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(default: Option<u64>, schedule: Schedule) -> Self {
+        Self {
+            default,
+            schedule,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// the rate in bytes/s at the given minute of the day, `None` for unlimited
+    fn rate_at(&self, minute: u32) -> Option<u64> {
+        self.schedule
+            .0
+            .iter()
+            .find(|w| w.contains(minute))
+            .map_or(self.default, |w| w.rate)
+    }
+
+    fn current_rate(&self) -> Option<u64> {
+        let now = Local::now();
+        self.rate_at(now.hour() * 60 + now.minute())
+    }
+
+    /// wait until `n` bytes may be transferred
+    pub async fn acquire(&self, n: u64) {
+        let Some(rate) = self.current_rate().filter(|&r| r > 0) else {
+            return;
+        };
+        let rate = rate as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            // allow bursts of at most one second
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last = now;
+            bucket.tokens -= n as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// parse a rate like `2M`, `500K` or `1048576` into bytes/s, `0` means unlimited
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().trim_end_matches("B") {
+        "" => 1.0,
+        "K" => 1024.0,
+        "M" => 1024.0 * 1024.0,
+        "G" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("无效的速率单位: {}", unit)),
+    };
+    let num: f64 = num
+        .trim()
+        .parse()
+        .map_err(|_| format!("无效的速率: {}", s))?;
+    if !num.is_finite() || num < 0.0 {
+        return Err(format!("无效的速率: {}", s));
+    }
+    Ok((num * multiplier) as u64)
+}
+
+/// parse a schedule like `01:00-07:00=0,19:00-23:00=1M`
+pub fn parse_schedule(s: &str) -> Result<Schedule, String> {
+    let parse_time = |t: &str| -> Result<u32, String> {
+        let time = NaiveTime::parse_from_str(t.trim(), "%H:%M")
+            .map_err(|_| format!("无效的时间: {}", t))?;
+        Ok(time.hour() * 60 + time.minute())
+    };
+
+    let mut windows = Vec::new();
+    for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+        let (range, rate) = part
+            .split_once('=')
+            .ok_or_else(|| format!("缺少速率: {}", part))?;
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| format!("无效的时间段: {}", range))?;
+        let rate = parse_rate(rate)?;
+        windows.push(Window {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            rate: (rate > 0).then_some(rate),
+        });
+    }
+    if windows.is_empty() {
+        return Err("未指定时间段".into());
+    }
+    Ok(Schedule(windows))
+}
+
+#[cfg(test)]
+mod limit_test {
+    use super::*;
+
+    #[test]
+    fn rate() {
+        assert_eq!(parse_rate("2M"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_rate("500k"), Ok(500 * 1024));
+        assert_eq!(parse_rate("1.5MB"), Ok(1536 * 1024));
+        assert_eq!(parse_rate("4096"), Ok(4096));
+        assert_eq!(parse_rate("0"), Ok(0));
+        assert!(parse_rate("2X").is_err());
+        assert!(parse_rate("-1M").is_err());
+    }
+
+    #[test]
+    fn schedule() {
+        let schedule = parse_schedule("01:00-07:00=0,23:00-01:00=1M").unwrap();
+        let limiter = RateLimiter::new(Some(2 << 20), schedule);
+        assert_eq!(limiter.rate_at(3 * 60), None);
+        assert_eq!(limiter.rate_at(12 * 60), Some(2 << 20));
+        assert_eq!(limiter.rate_at(23 * 60 + 30), Some(1 << 20));
+        assert_eq!(limiter.rate_at(30), Some(1 << 20));
+        assert_eq!(limiter.rate_at(7 * 60), Some(2 << 20));
+
+        assert!(parse_schedule("01:00-07:00").is_err());
+        assert!(parse_schedule("25:00-07:00=1M").is_err());
+    }
+
+    #[tokio::test]
+    async fn token_bucket() {
+        let limiter = RateLimiter::new(Some(100 * 1024), Schedule::default());
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire(50 * 1024).await;
+        }
+        // 200K at 100K/s with an empty bucket
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!(elapsed > 1.8 && elapsed < 2.5, "{}", elapsed);
+    }
+}
@@ -1,15 +1,56 @@
 use regex::Regex;
 use reqwest::Url;
+use serde_json::Value;
 
+pub mod cdn;
+pub mod checkpoint;
 pub mod client;
 pub mod download;
 pub mod fetch;
+pub mod limit;
+pub mod retry;
+pub mod stream;
+#[cfg(test)]
+pub mod test_server;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+/// check the `code` of a bilibili API response
+pub fn check_code(resp: &Value) -> Result<()> {
+    match resp["code"].as_i64() {
+        Some(0) | None => Ok(()),
+        Some(-10403) => Err(format!(
+            "地区限制: {}，可以通过 --api-proxy 使用对应地区的代理",
+            resp["message"].as_str().unwrap_or("当前地区不可观看")
+        )
+        .into()),
+        Some(code) => Err(format!(
+            "请求失败 ({}): {}",
+            code,
+            resp["message"].as_str().unwrap_or_default()
+        )
+        .into()),
+    }
+}
+
 pub fn url_regex(re: &str, url: &str) -> Option<String> {
     let u = Url::parse(url).unwrap();
     let re = Regex::new(re).unwrap();
     re.captures(&format!("{}/", u.path().trim_end_matches('/')))
         .map(|res| res.get(1).unwrap().as_str().to_owned())
 }
+
+#[cfg(test)]
+mod http_test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn region_limited() {
+        let resp = json!({"code": -10403, "message": "抱歉您所在地区不可观看！"});
+        let err = check_code(&resp).unwrap_err().to_string();
+        assert!(err.starts_with("地区限制"), "{}", err);
+        assert!(check_code(&json!({"code": 0, "result": {}})).is_ok());
+        assert!(check_code(&json!({"code": -404, "message": "啥都木有"})).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use reqwest::Url;
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// 失败重试的策略
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// 最大重试次数
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// exponential backoff with jitter, the delay before the `attempt`-th retry (starting from 1)
+    /// is a random value between half and all of `base_delay * 2^(attempt - 1)`
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        let half = exp / 2;
+        half + half.mul_f64(random())
+    }
+}
+
+/// a random number in [0, 1)
+fn random() -> f64 {
+    let n = RandomState::new().hash_one(SystemTime::now());
+    (n >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// whether the `deadline` query parameter of a stream url has passed
+pub fn is_expired(url: &str) -> bool {
+    let Ok(u) = Url::parse(url) else {
+        return false;
+    };
+    let deadline = u
+        .query_pairs()
+        .find(|(k, _)| k == "deadline")
+        .and_then(|(_, v)| v.parse::<u64>().ok());
+    match deadline {
+        Some(d) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            d <= now
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod retry_test {
+    use super::*;
+
+    #[test]
+    fn backoff_delay() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..=10 {
+            let exp = Duration::from_secs(1 << (attempt - 1)).min(policy.max_delay);
+            let d = policy.delay(attempt);
+            assert!(d >= exp / 2 && d <= exp, "attempt {}: {:?}", attempt, d);
+        }
+        assert!(policy.delay(100) <= policy.max_delay);
+    }
+
+    #[test]
+    fn deadline() {
+        assert!(is_expired(
+            "https://upos-sz-estgoss.bilivideo.com/1.m4s?e=x&deadline=1723464725&gen=playurlv2"
+        ));
+        assert!(!is_expired(
+            "https://upos-sz-estgoss.bilivideo.com/1.m4s?deadline=99999999999"
+        ));
+        assert!(!is_expired("https://upos-sz-estgoss.bilivideo.com/1.m4s"));
+    }
+}
@@ -0,0 +1,385 @@
+use futures_util::future::try_join_all;
+use reqwest::header::{CONTENT_RANGE, RANGE, REFERER};
+use reqwest::StatusCode;
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    future::Future,
+    io::SeekFrom,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+use super::cdn::url_host;
+use super::checkpoint::{Checkpoint, Ranges};
+use super::client::Client;
+use super::download::DownloadOptions;
+use super::retry::is_expired;
+use crate::progress::{error, info, MediaProgress, TaskProgress};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// 小于该大小的流只使用一个连接下载
+const SPLIT_THRESHOLD: u64 = 8 << 20;
+const MIN_PIECE: u64 = 1 << 20;
+const MAX_PIECE: u64 = 32 << 20;
+/// 根据上一段的下载速度调整分段大小，使每段大约需要的下载时间
+const PIECE_SECS: f64 = 2.0;
+
+/// the CDN answered with an unexpected status
+#[derive(Debug)]
+pub struct HttpStatus(pub StatusCode);
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "download failed with status: {}", self.0)
+    }
+}
+
+impl Error for HttpStatus {}
+
+/// the download was interrupted through the cancellation token
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "下载已取消")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// where the downloaded bytes go
+struct Output<'b> {
+    path: &'b Path,
+    pb: &'b MediaProgress<'b>,
+    done: &'b Mutex<Ranges>,
+}
+
+/// A media stream served by several CDN urls.
+///
+/// Every request fails over to the next url on errors, stalls or bad status, every round over
+/// all urls is retried with backoff, and the urls are refreshed through `refresh` when they
+/// expire or the CDN answers 403
+pub struct MediaStream<'a, F> {
+    client: &'a Client,
+    referer: &'a str,
+    media: &'a str,
+    options: &'a DownloadOptions,
+    urls: Mutex<Vec<String>>,
+    /// bumped on every refresh so that concurrent connections only refresh once
+    generation: AtomicU64,
+    refresh: F,
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl<'a, F, Fut> MediaStream<'a, F>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    pub fn new(
+        client: &'a Client,
+        referer: &'a str,
+        media: &'a str,
+        options: &'a DownloadOptions,
+        urls: Vec<String>,
+        refresh: F,
+    ) -> Self {
+        Self {
+            client,
+            referer,
+            media,
+            options,
+            urls: Mutex::new(urls),
+            generation: AtomicU64::new(0),
+            refresh,
+            refreshing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// download the whole stream into `path`, splitting it over several connections when the
+    /// server supports range requests.
+    ///
+    /// The downloaded ranges are saved as a checkpoint beside `path` when the download stops,
+    /// either finished, failed or cancelled through `token`, and a matching checkpoint is resumed
+    pub async fn download_to(
+        &self,
+        path: &Path,
+        progress: &TaskProgress,
+        token: &CancellationToken,
+    ) -> Result<()> {
+        let (total, ranged) = tokio::select! {
+            res = self.round(|url| self.probe(url)) => res?,
+            _ = token.cancelled() => return Err(Cancelled.into()),
+        };
+
+        let done = match Checkpoint::load(path) {
+            Some(c) if ranged && c.total == total => {
+                info!("[{}] 继续未完成的下载", self.media);
+                c.done
+            }
+            _ => {
+                let file = File::create(path).await?;
+                file.set_len(total).await?;
+                Ranges::default()
+            }
+        };
+
+        let pb = MediaProgress::new(progress, self.media, total).resumed(done.bytes());
+        let done = Mutex::new(done);
+        let out = Output {
+            path,
+            pb: &pb,
+            done: &done,
+        };
+        let res = tokio::select! {
+            res = self.fetch_missing(&out, total, ranged) => res,
+            _ = token.cancelled() => Err(Cancelled.into()),
+        };
+
+        if ranged {
+            let checkpoint = Checkpoint {
+                total,
+                done: done.into_inner().unwrap(),
+            };
+            if let Err(e) = checkpoint.save(path) {
+                error!("[{}] 保存下载进度失败: {}", self.media, e);
+            }
+        }
+        if res.is_ok() {
+            pb.finish();
+        }
+        res
+    }
+
+    async fn fetch_missing(&self, out: &Output<'_>, total: u64, ranged: bool) -> Result<()> {
+        let missing = out.done.lock().unwrap().missing(total);
+        let connections = if ranged && total >= SPLIT_THRESHOLD {
+            self.options.connections.max(1)
+        } else {
+            1
+        };
+        if connections == 1 {
+            for (start, end) in missing {
+                self.fetch_piece(out, start, end, !ranged).await?;
+            }
+        } else {
+            let queue = Mutex::new(VecDeque::from(missing));
+            try_join_all((0..connections).map(|_| self.worker(out, &queue))).await?;
+        }
+        Ok(())
+    }
+
+    /// repeatedly take the next piece of the missing ranges and download it, adapting the piece
+    /// size to the measured throughput
+    async fn worker(&self, out: &Output<'_>, queue: &Mutex<VecDeque<(u64, u64)>>) -> Result<()> {
+        let mut piece = MIN_PIECE;
+        loop {
+            let (start, end) = {
+                let mut q = queue.lock().unwrap();
+                let Some(range) = q.front_mut() else {
+                    return Ok(());
+                };
+                let start = range.0;
+                let end = (start + piece).min(range.1);
+                if end == range.1 {
+                    q.pop_front();
+                } else {
+                    range.0 = end;
+                }
+                (start, end)
+            };
+            let t = Instant::now();
+            self.fetch_piece(out, start, end, false).await?;
+            let secs = t.elapsed().as_secs_f64().max(0.001);
+            piece = (((end - start) as f64 / secs * PIECE_SECS) as u64).clamp(MIN_PIECE, MAX_PIECE);
+        }
+    }
+
+    /// download bytes `[start, end)`, resuming from where a failed attempt stopped.
+    /// With `whole`, the piece is the entire stream and a plain 200 response is accepted
+    async fn fetch_piece(&self, out: &Output<'_>, start: u64, end: u64, whole: bool) -> Result<()> {
+        let pos = AtomicU64::new(start);
+        self.round(|url| self.write_range(url, out, &pos, end, whole))
+            .await
+    }
+
+    /// get the total size of the stream and whether range requests are supported
+    async fn probe(&self, url: String) -> Result<(u64, bool)> {
+        let req = self
+            .client
+            .get_media(&url)
+            .header(REFERER, self.referer)
+            .header(RANGE, "bytes=0-0");
+        let resp = timeout(self.options.stall_timeout, req.send())
+            .await
+            .map_err(|_| "连接超时")??;
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let total = resp
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.rsplit('/').next())
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("Failed to get content range")?;
+                Ok((total, true))
+            }
+            StatusCode::OK => {
+                let total = resp
+                    .content_length()
+                    .ok_or("Failed to get content length")?;
+                Ok((total, false))
+            }
+            status => Err(HttpStatus(status).into()),
+        }
+    }
+
+    async fn write_range(
+        &self,
+        url: String,
+        out: &Output<'_>,
+        pos: &AtomicU64,
+        end: u64,
+        whole: bool,
+    ) -> Result<()> {
+        let stall_timeout = self.options.stall_timeout;
+        let mut start = pos.load(Ordering::Relaxed);
+        let mut req = self.client.get_media(&url).header(REFERER, self.referer);
+        if start > 0 || !whole {
+            req = req.header(RANGE, format!("bytes={}-{}", start, end - 1));
+        }
+        let mut resp = timeout(stall_timeout, req.send())
+            .await
+            .map_err(|_| "连接超时")??;
+
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            StatusCode::OK if whole => {
+                if start > 0 {
+                    // the server ignored the range, start over
+                    start = 0;
+                    pos.store(0, Ordering::Relaxed);
+                    *out.done.lock().unwrap() = Ranges::default();
+                    out.pb.reset();
+                }
+            }
+            status => return Err(HttpStatus(status).into()),
+        }
+
+        let mut file = OpenOptions::new().write(true).open(out.path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        while start < end {
+            let Some(chunk) = timeout(stall_timeout, resp.chunk())
+                .await
+                .map_err(|_| "下载停滞")??
+            else {
+                break;
+            };
+            let n = chunk.len().min((end - start) as usize);
+            if let Some(limiter) = &self.options.limiter {
+                limiter.acquire(n as u64).await;
+            }
+            file.write_all(&chunk[..n]).await?;
+            out.done.lock().unwrap().insert(start, start + n as u64);
+            start += n as u64;
+            pos.store(start, Ordering::Relaxed);
+            out.pb.inc(n as u64);
+        }
+        file.flush().await?;
+
+        if start < end {
+            return Err("连接中断".into());
+        }
+        Ok(())
+    }
+
+    /// run `op` against every url in turn until one succeeds, retrying the whole round with
+    /// backoff
+    async fn round<T, Op, OpFut>(&self, mut op: Op) -> Result<T>
+    where
+        Op: FnMut(String) -> OpFut,
+        OpFut: Future<Output = Result<T>>,
+    {
+        let retry = self.client.retry();
+        let mut last_err: Box<dyn Error + Send + Sync> = "没有可用的下载链接".into();
+        for attempt in 0..=retry.retries {
+            if attempt > 0 {
+                let delay = retry.delay(attempt);
+                error!(
+                    "[{}] {:.1}s 后重试 ({}/{})",
+                    self.media,
+                    delay.as_secs_f64(),
+                    attempt,
+                    retry.retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+
+            let (mut urls, mut generation) = self.current_urls();
+            if urls.iter().all(|u| is_expired(u)) {
+                if let Err(e) = self.refresh_urls(generation).await {
+                    last_err = e;
+                    continue;
+                }
+                (urls, generation) = self.current_urls();
+            }
+
+            for (i, url) in urls.into_iter().enumerate() {
+                if i > 0 {
+                    error!("[{}] 切换到备用链接: {}", self.media, url_host(&url));
+                }
+                let host = url_host(&url);
+                match op(url).await {
+                    Ok(v) => return Ok(v),
+                    Err(e) => {
+                        error!("[{}] {} 下载失败: {}", self.media, host, e);
+                        let forbidden = matches!(
+                            e.downcast_ref::<HttpStatus>(),
+                            Some(HttpStatus(StatusCode::FORBIDDEN))
+                        );
+                        last_err = e;
+                        if forbidden {
+                            if let Err(e) = self.refresh_urls(generation).await {
+                                last_err = e;
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn current_urls(&self) -> (Vec<String>, u64) {
+        let urls = self.urls.lock().unwrap().clone();
+        (urls, self.generation.load(Ordering::Acquire))
+    }
+
+    async fn refresh_urls(&self, seen: u64) -> Result<()> {
+        let _guard = self.refreshing.lock().await;
+        if self.generation.load(Ordering::Acquire) != seen {
+            // already refreshed by another connection
+            return Ok(());
+        }
+        info!("[{}] 下载链接已过期，重新获取播放信息", self.media);
+        let urls = (self.refresh)().await?;
+        *self.urls.lock().unwrap() = urls;
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+}
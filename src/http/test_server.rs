@@ -0,0 +1,101 @@
+//! Minimal HTTP stand-in for CDN nodes, only used in tests
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Clone, Copy)]
+pub enum Behaviour {
+    /// serve the body, honouring `Range` requests
+    Ok,
+    /// reply with the given status and an empty body
+    Status(u16),
+    /// send the headers and then stop responding
+    Stall,
+    /// send the headers and the first n bytes, then close the connection
+    Truncate(usize),
+}
+
+/// start a server on a random local port and return its base url
+pub async fn serve(body: &'static [u8], behaviour: Behaviour) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(handle(socket, body, behaviour));
+        }
+    });
+    format!("http://{}/upgcxcode/media.m4s", addr)
+}
+
+async fn handle(mut socket: TcpStream, body: &'static [u8], behaviour: Behaviour) {
+    let mut buf = Vec::new();
+    let mut tmp = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        match socket.read(&mut tmp).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&tmp[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&buf).to_lowercase();
+    let range = request
+        .lines()
+        .find_map(|l| l.strip_prefix("range: bytes="))
+        .and_then(|r| r.trim().split_once('-'))
+        .and_then(|(start, end)| {
+            let start = start.parse::<usize>().ok()?;
+            let end = end
+                .parse::<usize>()
+                .map_or(body.len() - 1, |e| e.min(body.len() - 1));
+            Some((start, end))
+        });
+
+    let (status, part) = match (behaviour, range) {
+        (Behaviour::Status(code), _) => {
+            let head = format!("HTTP/1.1 {} Error\r\nContent-Length: 0\r\n\r\n", code);
+            let _ = socket.write_all(head.as_bytes()).await;
+            return;
+        }
+        (_, Some((start, end))) => (
+            format!(
+                "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                start,
+                end,
+                body.len()
+            ),
+            &body[start..=end],
+        ),
+        (_, None) => ("200 OK".to_string(), body),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        part.len()
+    );
+    if socket.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+
+    match behaviour {
+        Behaviour::Stall => tokio::time::sleep(Duration::from_secs(60)).await,
+        Behaviour::Truncate(n) => {
+            let _ = socket.write_all(&part[..n.min(part.len())]).await;
+        }
+        _ => {
+            let _ = socket.write_all(part).await;
+        }
+    }
+}
+
+/// an empty folder for a test, unique to the test `name` and the process
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bili-dl-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,522 @@
+//! 录制直播时保存弹幕、礼物和醒目留言，并转换为与录制文件对齐的 ASS 字幕
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Local;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::header::USER_AGENT;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, WebSocketStream};
+use tokio_util::sync::CancellationToken;
+
+use super::packet::{decode, encode, Packet, OP_AUTH, OP_HEARTBEAT};
+use crate::http::client::{Client, UA};
+use crate::http::{check_code, Result};
+use crate::progress::{error, info};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// no message at all, not even a heartbeat reply, for this long means the connection is dead
+const READ_TIMEOUT: Duration = Duration::from_secs(70);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// 一条弹幕、礼物或醒目留言
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    /// 收到消息时的时间戳，单位为毫秒
+    pub timestamp: i64,
+    pub uid: i64,
+    pub user: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    Danmaku {
+        text: String,
+        /// RGB 颜色，如 `0xffffff`
+        color: u32,
+    },
+    Gift {
+        gift: String,
+        count: u32,
+        /// 总价值，单位为元，免费礼物为 0
+        value: f64,
+    },
+    SuperChat {
+        text: String,
+        /// 单位为元
+        price: u32,
+        /// 留言的展示时长，单位为秒
+        duration: u64,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct DanmuInfo {
+    token: String,
+    host_list: Vec<DanmuHost>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DanmuHost {
+    host: String,
+    wss_port: u16,
+}
+
+impl Client {
+    /// the token and servers of the danmaku websocket
+    async fn fetch_danmu_info(&self, room_id: i64) -> Result<DanmuInfo> {
+        let url = format!(
+            "https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo?id={}&type=0",
+            room_id
+        );
+        let mut resp: Value = self.send(self.get(&url)).await?.json().await?;
+        check_code(&resp)?;
+        Ok(serde_json::from_value(resp["data"].take())?)
+    }
+}
+
+/// append every event of the room to `path` as JSON lines until `token` is cancelled,
+/// reconnecting when the connection drops
+pub async fn capture(
+    client: &Client,
+    room_id: i64,
+    path: &Path,
+    token: &CancellationToken,
+) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    info!("[Live] 保存弹幕到 {}", path.display());
+    while !token.is_cancelled() {
+        if let Err(e) = capture_once(client, room_id, &mut file, token).await {
+            error!("弹幕连接断开: {}", e);
+        }
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep(RETRY_DELAY) => {}
+        }
+    }
+    Ok(())
+}
+
+async fn capture_once(
+    client: &Client,
+    room_id: i64,
+    out: &mut impl Write,
+    token: &CancellationToken,
+) -> Result<()> {
+    let info = client.fetch_danmu_info(room_id).await?;
+    let mut last_err = "没有可用的弹幕服务器".into();
+    for host in info.host_list.iter() {
+        let mut req = format!("wss://{}:{}/sub", host.host, host.wss_port).into_client_request()?;
+        req.headers_mut().insert(USER_AGENT, UA.parse()?);
+        match connect_async(req).await {
+            Ok((ws, _)) => return listen(ws, room_id, &info.token, out, token).await,
+            Err(e) => last_err = e.into(),
+        }
+    }
+    Err(last_err)
+}
+
+/// authenticate, then keep the connection alive and write the events until it closes
+async fn listen<S>(
+    mut ws: WebSocketStream<S>,
+    room_id: i64,
+    key: &str,
+    out: &mut impl Write,
+    token: &CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // uid 0 joins as a guest, the names of other users may be masked then
+    let auth = json!({
+        "uid": 0, "roomid": room_id, "protover": 3, "platform": "web", "type": 2, "key": key,
+    });
+    ws.send(Message::Binary(encode(
+        OP_AUTH,
+        auth.to_string().as_bytes(),
+    )))
+    .await?;
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        let msg = tokio::select! {
+            _ = token.cancelled() => {
+                let _ = ws.close(None).await;
+                return Ok(());
+            }
+            _ = heartbeat.tick() => {
+                ws.send(Message::Binary(encode(OP_HEARTBEAT, b"[object Object]"))).await?;
+                continue;
+            }
+            msg = tokio::time::timeout(READ_TIMEOUT, ws.next()) => msg,
+        };
+        let data = match msg.map_err(|_| "弹幕服务器超时没有响应")? {
+            Some(Ok(Message::Binary(data))) => data,
+            Some(Ok(Message::Close(_))) | None => return Err("弹幕服务器关闭了连接".into()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        };
+        let timestamp = Local::now().timestamp_millis();
+        for packet in decode(&data)? {
+            match packet {
+                Packet::AuthReply(reply) => match reply["code"].as_i64() {
+                    Some(0) => info!("[Live] 已连接弹幕服务器"),
+                    _ => return Err(format!("弹幕服务器认证失败: {}", reply).into()),
+                },
+                Packet::Command(cmd) => {
+                    if let Some(event) = parse_event(&cmd, timestamp) {
+                        writeln!(out, "{}", serde_json::to_string(&event)?)?;
+                        out.flush()?;
+                    }
+                }
+                Packet::HeartbeatReply(_) => {}
+            }
+        }
+    }
+}
+
+/// the event of a danmaku, gift or SuperChat command, other commands are ignored
+fn parse_event(cmd: &Value, timestamp: i64) -> Option<Event> {
+    // the name can carry extra fields like `DANMU_MSG:4:0:2:2:2:0`
+    let name = cmd["cmd"].as_str()?.split(':').next()?;
+    let data = &cmd["data"];
+    let event = match name {
+        "DANMU_MSG" => {
+            let info = &cmd["info"];
+            Event {
+                timestamp,
+                uid: info[2][0].as_i64()?,
+                user: info[2][1].as_str()?.to_owned(),
+                kind: EventKind::Danmaku {
+                    text: info[1].as_str()?.to_owned(),
+                    color: info[0][3].as_u64().unwrap_or(0xffffff) as u32,
+                },
+            }
+        }
+        "SEND_GIFT" => {
+            let count = data["num"].as_u64().unwrap_or(1) as u32;
+            // the price of a paid gift is in gold coins, 1000 for one yuan
+            let value = match data["coin_type"].as_str() {
+                Some("gold") => data["price"].as_f64().unwrap_or_default() * count as f64 / 1000.0,
+                _ => 0.0,
+            };
+            Event {
+                timestamp,
+                uid: data["uid"].as_i64()?,
+                user: data["uname"].as_str()?.to_owned(),
+                kind: EventKind::Gift {
+                    gift: data["giftName"].as_str()?.to_owned(),
+                    count,
+                    value,
+                },
+            }
+        }
+        "SUPER_CHAT_MESSAGE" => Event {
+            timestamp,
+            uid: data["uid"].as_i64()?,
+            user: data["user_info"]["uname"].as_str()?.to_owned(),
+            kind: EventKind::SuperChat {
+                text: data["message"].as_str()?.to_owned(),
+                price: data["price"].as_u64()? as u32,
+                duration: data["time"].as_u64().unwrap_or_default(),
+            },
+        },
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// the events saved by [`capture`], a line cut off by an interruption is skipped
+pub fn read_events(path: &Path) -> Result<Vec<Event>> {
+    let s = fs::read_to_string(path)?;
+    Ok(s.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// write the events received while a segment was recorded as ASS subtitles next to it,
+/// returning how many there are. Nothing is written when there is none
+pub fn write_ass(events: &[Event], start: i64, end: i64, o_path: &Path) -> Result<usize> {
+    let events: Vec<&Event> = events
+        .iter()
+        .filter(|e| (start..end).contains(&e.timestamp))
+        .collect();
+    if !events.is_empty() {
+        fs::write(o_path, to_ass(&events, start))?;
+    }
+    Ok(events.len())
+}
+
+const PLAY_RES_X: i64 = 1920;
+const PLAY_RES_Y: i64 = 1080;
+const FONT_SIZE: i64 = 50;
+const LINE_HEIGHT: i64 = 56;
+/// scrolling danmaku use the top three quarters of the screen
+const LANES: usize = (PLAY_RES_Y * 3 / 4 / LINE_HEIGHT) as usize;
+/// the time a danmaku takes to cross the screen, in milliseconds
+const SCROLL_TIME: i64 = 8000;
+const GIFT_TIME: i64 = 5000;
+/// SuperChats stay longer, but not as long as in the room
+const SUPER_CHAT_TIME: i64 = 10000;
+
+/// scrolling danmaku from right to left, gifts and SuperChats stacked at the bottom left
+fn to_ass(events: &[&Event], start: i64) -> String {
+    let mut ass = format!(
+        "[Script Info]\n\
+         ScriptType: v4.00+\n\
+         PlayResX: {PLAY_RES_X}\n\
+         PlayResY: {PLAY_RES_Y}\n\
+         WrapStyle: 2\n\
+         \n\
+         [V4+ Styles]\n\
+         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: Danmaku,Microsoft YaHei,{FONT_SIZE},&H33FFFFFF,&H33FFFFFF,&H33000000,&H00000000,0,0,0,0,100,100,0,0,1,2,0,7,0,0,0,1\n\
+         Style: Notice,Microsoft YaHei,40,&H00FFFFFF,&H00FFFFFF,&H00000000,&H80000000,1,0,0,0,100,100,0,0,1,2,0,1,20,20,20,1\n\
+         \n\
+         [Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n"
+    );
+    // the start time and width of the last danmaku in every lane
+    let mut lanes: Vec<Option<(i64, i64)>> = vec![None; LANES];
+    for event in events {
+        let t = event.timestamp - start;
+        let (style, end, text) = match &event.kind {
+            EventKind::Danmaku { text, color } => {
+                let width = text_width(text);
+                let lane = pick_lane(&mut lanes, t, width);
+                let y = lane as i64 * LINE_HEIGHT;
+                let color = match *color {
+                    0xffffff => String::new(),
+                    c => format!(
+                        "\\c&H{:02X}{:02X}{:02X}&",
+                        c & 0xff,
+                        (c >> 8) & 0xff,
+                        c >> 16
+                    ),
+                };
+                let text = format!(
+                    "{{\\move({},{},{},{}){}}}{}",
+                    PLAY_RES_X,
+                    y,
+                    -width,
+                    y,
+                    color,
+                    escape(text)
+                );
+                ("Danmaku", t + SCROLL_TIME, text)
+            }
+            EventKind::Gift { gift, count, .. } => (
+                "Notice",
+                t + GIFT_TIME,
+                format!("{} 投喂 {} ×{}", escape(&event.user), escape(gift), count),
+            ),
+            EventKind::SuperChat { text, price, .. } => (
+                "Notice",
+                t + SUPER_CHAT_TIME,
+                format!(
+                    "{{\\c&H00C0FF&}}[SC ¥{}] {}{{\\c}}: {}",
+                    price,
+                    escape(&event.user),
+                    escape(text)
+                ),
+            ),
+        };
+        ass.push_str(&format!(
+            "Dialogue: 0,{},{},{},,0,0,0,,{}\n",
+            ass_time(t),
+            ass_time(end),
+            style,
+            text
+        ));
+    }
+    ass
+}
+
+/// the first lane where the danmaku neither overlaps the one before it when it enters
+/// nor catches up with it before it leaves, or the lane that has been free the longest
+fn pick_lane(lanes: &mut [Option<(i64, i64)>], t: i64, width: i64) -> usize {
+    // the time to move by `distance` for a danmaku of `width`, it crosses `PLAY_RES_X + width`
+    let time = |distance: i64, width: i64| distance * SCROLL_TIME / (PLAY_RES_X + width);
+    let lane = lanes
+        .iter()
+        .position(|lane| match *lane {
+            None => true,
+            Some((s, w)) => t - s >= time(w, w) && t + time(PLAY_RES_X, width) >= s + SCROLL_TIME,
+        })
+        .unwrap_or_else(|| {
+            (0..lanes.len())
+                .min_by_key(|&i| lanes[i].map(|(s, _)| s))
+                .unwrap_or_default()
+        });
+    lanes[lane] = Some((t, width));
+    lane
+}
+
+/// an estimate without the font: full width for CJK characters, half for the rest
+fn text_width(text: &str) -> i64 {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii() {
+                FONT_SIZE / 2
+            } else {
+                FONT_SIZE
+            }
+        })
+        .sum()
+}
+
+/// keep override blocks and line breaks typed by users from being interpreted
+fn escape(text: &str) -> String {
+    text.replace('\\', "＼")
+        .replace('{', "｛")
+        .replace('}', "｝")
+        .replace(['\r', '\n'], " ")
+}
+
+/// `H:MM:SS.cc`
+fn ass_time(ms: i64) -> String {
+    let cs = ms.max(0) / 10;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+#[cfg(test)]
+mod danmaku_test {
+    use super::*;
+    use crate::live::packet::packet_test::{AUTH_REPLY, BROTLI, HEARTBEAT_REPLY, ZLIB};
+    use tokio::net::TcpListener;
+
+    fn events() -> Vec<Event> {
+        [ZLIB, BROTLI]
+            .iter()
+            .flat_map(|b| decode(b).unwrap())
+            .zip(0..)
+            .filter_map(|(p, i)| match p {
+                Packet::Command(cmd) => parse_event(&cmd, 10_000 + i * 1000),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_events() {
+        let events = events();
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events[0],
+            Event {
+                timestamp: 10_000,
+                uid: 1001,
+                user: "观众甲".into(),
+                kind: EventKind::Danmaku {
+                    text: "主播晚上好".into(),
+                    color: 0xffffff,
+                },
+            }
+        );
+        assert_eq!(
+            events[1].kind,
+            EventKind::Gift {
+                gift: "小花花".into(),
+                count: 3,
+                value: 0.3,
+            }
+        );
+        assert_eq!(
+            events[3],
+            Event {
+                timestamp: 13_000,
+                uid: 1004,
+                user: "观众丁".into(),
+                kind: EventKind::SuperChat {
+                    text: "第一次看直播，支持一下".into(),
+                    price: 30,
+                    duration: 60,
+                },
+            }
+        );
+        let line = serde_json::to_string(&events[3]).unwrap();
+        assert!(line.contains(r#""type":"super_chat""#), "{}", line);
+        assert_eq!(serde_json::from_str::<Event>(&line).unwrap(), events[3]);
+        assert!(parse_event(&json!({"cmd": "INTERACT_WORD", "data": {}}), 0).is_none());
+    }
+
+    #[test]
+    fn convert_to_ass() {
+        let events = events();
+        // a segment from 11s to 14s sees the gift, the red danmaku and the SuperChat
+        let segment: Vec<&Event> = events
+            .iter()
+            .filter(|e| (11_000..14_000).contains(&e.timestamp))
+            .collect();
+        let ass = to_ass(&segment, 11_000);
+        let lines: Vec<&str> = ass.lines().filter(|l| l.starts_with("Dialogue")).collect();
+        assert_eq!(
+            lines,
+            [
+                "Dialogue: 0,0:00:00.00,0:00:05.00,Notice,,0,0,0,,观众乙 投喂 小花花 ×3",
+                "Dialogue: 0,0:00:01.00,0:00:09.00,Danmaku,,0,0,0,,{\\move(1920,0,-300,0)\\c&H0000FF&}红色的｛弹幕｝",
+                "Dialogue: 0,0:00:02.00,0:00:12.00,Notice,,0,0,0,,{\\c&H00C0FF&}[SC ¥30] 观众丁{\\c}: 第一次看直播，支持一下",
+            ]
+        );
+        assert_eq!(ass_time(3_723_456), "1:02:03.45");
+    }
+
+    #[test]
+    fn danmaku_lanes() {
+        let mut lanes = vec![None; 3];
+        let width = text_width("弹幕弹幕");
+        assert_eq!(pick_lane(&mut lanes, 0, width), 0);
+        // the first one has not fully entered the screen yet
+        assert_eq!(pick_lane(&mut lanes, 100, width), 1);
+        // a long one right after would catch up with the one in lane 0
+        assert_eq!(pick_lane(&mut lanes, 1000, text_width(&"长".repeat(30))), 2);
+        assert_eq!(pick_lane(&mut lanes, 1000, width), 0);
+        // every lane is taken, the oldest one is reused
+        assert_eq!(pick_lane(&mut lanes, 1100, text_width(&"长".repeat(30))), 1);
+    }
+
+    #[tokio::test]
+    async fn listen_to_local_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let Some(Ok(Message::Binary(auth))) = ws.next().await else {
+                panic!("no auth packet");
+            };
+            let body: Value = serde_json::from_slice(&auth[16..]).unwrap();
+            assert_eq!(body["key"], "token");
+            for msg in [AUTH_REPLY, HEARTBEAT_REPLY, ZLIB, BROTLI] {
+                ws.send(Message::Binary(msg.to_vec())).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+        });
+        let (ws, _) = connect_async(format!("ws://{}/sub", addr)).await.unwrap();
+        let mut out = Vec::new();
+        let res = listen(ws, 1, "token", &mut out, &CancellationToken::new()).await;
+        assert!(res.is_err());
+        let lines: Vec<Event> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[4].kind, events()[4].kind);
+    }
+}
@@ -0,0 +1,282 @@
+//! 将 FLV 直播流按大小或时长切分为可以单独播放的文件
+
+use std::time::Duration;
+
+use crate::http::Result;
+
+/// `FLV` header and the `PreviousTagSize0` after it
+const HEADER_LEN: usize = 9 + 4;
+const TAG_HEADER_LEN: usize = 11;
+
+const TAG_AUDIO: u8 = 8;
+const TAG_VIDEO: u8 = 9;
+const TAG_SCRIPT: u8 = 18;
+
+/// 分段的大小和时长上限，达到任意一个时在下一个关键帧处开始新的分段
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SegmentLimit {
+    /// 单位为字节
+    pub size: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+impl SegmentLimit {
+    pub fn reached(&self, size: u64, duration: Duration) -> bool {
+        self.size.is_some_and(|s| size >= s) || self.time.is_some_and(|t| duration >= t)
+    }
+}
+
+/// 切分后的数据
+#[derive(Debug, PartialEq)]
+pub enum Chunk {
+    /// 开始新的分段，之后的数据写入新的文件
+    Start,
+    Data(Vec<u8>),
+}
+
+struct Segment {
+    size: u64,
+    /// the timestamp of the first tag, every tag in the segment is moved earlier by it
+    base: u32,
+    last: u32,
+}
+
+/// Splits a FLV stream into segments which each start with the file header, the metadata
+/// and the codec sequence headers, then a keyframe at timestamp 0
+pub struct FlvSplitter {
+    limit: SegmentLimit,
+    buf: Vec<u8>,
+    /// the file header, `None` until it is received
+    header: Option<Vec<u8>>,
+    has_video: bool,
+    /// the latest script tag and sequence headers by tag type, repeated in every segment
+    sequence: Vec<(u8, Vec<u8>)>,
+    segment: Option<Segment>,
+}
+
+impl FlvSplitter {
+    pub fn new(limit: SegmentLimit) -> Self {
+        Self {
+            limit,
+            buf: Vec::new(),
+            header: None,
+            has_video: false,
+            sequence: Vec::new(),
+            segment: None,
+        }
+    }
+
+    /// parse the received bytes, a tag split across calls is kept until the rest arrives
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<Chunk>> {
+        self.buf.extend_from_slice(bytes);
+        let mut chunks = Vec::new();
+        let mut pos = 0;
+        if self.header.is_none() {
+            if self.buf.len() < HEADER_LEN {
+                return Ok(chunks);
+            }
+            if &self.buf[..3] != b"FLV" {
+                return Err("不是 FLV 格式的直播流".into());
+            }
+            self.has_video = self.buf[4] & 0x01 != 0;
+            self.header = Some(self.buf[..HEADER_LEN].to_vec());
+            pos = HEADER_LEN;
+        }
+        while self.buf.len() - pos >= TAG_HEADER_LEN {
+            let head = &self.buf[pos..pos + TAG_HEADER_LEN];
+            let size = u32::from_be_bytes([0, head[1], head[2], head[3]]) as usize;
+            let end = pos + TAG_HEADER_LEN + size + 4;
+            if self.buf.len() < end {
+                break;
+            }
+            let tag_type = head[0] & 0x1f;
+            let timestamp = u32::from_be_bytes([head[7], head[4], head[5], head[6]]);
+            let data = self.buf[pos + TAG_HEADER_LEN..end - 4].to_vec();
+            self.tag(tag_type, timestamp, data, &mut chunks);
+            pos = end;
+        }
+        self.buf.drain(..pos);
+        Ok(chunks)
+    }
+
+    fn tag(&mut self, tag_type: u8, timestamp: u32, data: Vec<u8>, chunks: &mut Vec<Chunk>) {
+        if is_sequence(tag_type, &data) {
+            if let Some(segment) = &self.segment {
+                // the codec changed in the middle of a segment
+                let ts = timestamp.saturating_sub(segment.base);
+                write(chunks, &tag_bytes(tag_type, ts, &data));
+            }
+            self.sequence.retain(|(t, _)| *t != tag_type);
+            self.sequence.push((tag_type, data));
+            self.sequence.sort_by_key(|(t, _)| std::cmp::Reverse(*t));
+            return;
+        }
+        if tag_type != TAG_AUDIO && tag_type != TAG_VIDEO {
+            return;
+        }
+        let can_split = !self.has_video || (tag_type == TAG_VIDEO && is_keyframe(&data));
+        let split = match &self.segment {
+            None => true,
+            Some(s) => {
+                let duration = Duration::from_millis(s.last.saturating_sub(s.base) as u64);
+                self.limit.reached(s.size, duration)
+            }
+        };
+        if split && can_split {
+            chunks.push(Chunk::Start);
+            let mut start = self.header.clone().unwrap_or_default();
+            for (t, data) in self.sequence.iter() {
+                start.extend(tag_bytes(*t, 0, data));
+            }
+            write(chunks, &start);
+            self.segment = Some(Segment {
+                size: start.len() as u64,
+                base: timestamp,
+                last: timestamp,
+            });
+        }
+        // everything before the first keyframe can not be played
+        let Some(segment) = &mut self.segment else {
+            return;
+        };
+        let bytes = tag_bytes(tag_type, timestamp.saturating_sub(segment.base), &data);
+        segment.size += bytes.len() as u64;
+        segment.last = segment.last.max(timestamp);
+        write(chunks, &bytes);
+    }
+}
+
+/// append to the data of the current segment
+fn write(chunks: &mut Vec<Chunk>, bytes: &[u8]) {
+    match chunks.last_mut() {
+        Some(Chunk::Data(data)) => data.extend_from_slice(bytes),
+        _ => chunks.push(Chunk::Data(bytes.to_vec())),
+    }
+}
+
+/// a whole tag with its trailing `PreviousTagSize`
+fn tag_bytes(tag_type: u8, timestamp: u32, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(TAG_HEADER_LEN + data.len() + 4);
+    bytes.push(tag_type);
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    let ts = timestamp.to_be_bytes();
+    bytes.extend_from_slice(&[ts[1], ts[2], ts[3], ts[0]]);
+    bytes.extend_from_slice(&[0, 0, 0]);
+    bytes.extend_from_slice(data);
+    bytes.extend_from_slice(&((TAG_HEADER_LEN + data.len()) as u32).to_be_bytes());
+    bytes
+}
+
+/// the metadata, or an AVC/HEVC/AAC sequence header
+fn is_sequence(tag_type: u8, data: &[u8]) -> bool {
+    match tag_type {
+        TAG_SCRIPT => true,
+        // enhanced FLV keeps the packet type in the low bits of the first byte
+        TAG_VIDEO if data.first().is_some_and(|b| b & 0x80 != 0) => data[0] & 0x0f == 0,
+        TAG_VIDEO => data.len() > 1 && matches!(data[0] & 0x0f, 7 | 12) && data[1] == 0,
+        TAG_AUDIO => data.len() > 1 && data[0] >> 4 == 10 && data[1] == 0,
+        _ => false,
+    }
+}
+
+fn is_keyframe(data: &[u8]) -> bool {
+    data.first().is_some_and(|b| (b >> 4) & 0x07 == 1)
+}
+
+#[cfg(test)]
+pub mod flv_test {
+    use super::*;
+
+    /// a stream with the metadata, both sequence headers, then a frame every 40ms
+    /// with a keyframe every `gop` frames and an audio tag after each frame
+    pub fn sample(frames: u32, gop: u32) -> Vec<u8> {
+        let mut bytes = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+        bytes.extend(tag_bytes(TAG_SCRIPT, 0, b"onMetaData"));
+        bytes.extend(tag_bytes(TAG_VIDEO, 0, &[0x17, 0, 0, 0, 0, 1]));
+        bytes.extend(tag_bytes(TAG_AUDIO, 0, &[0xaf, 0, 0x12, 0x10]));
+        for i in 0..frames {
+            let ts = 1000 + i * 40;
+            let frame = if i % gop == 0 { 0x17 } else { 0x27 };
+            bytes.extend(tag_bytes(TAG_VIDEO, ts, &[frame, 1, 0, 0, 0, 9, 9, 9]));
+            bytes.extend(tag_bytes(TAG_AUDIO, ts, &[0xaf, 1, 7, 7]));
+        }
+        bytes
+    }
+
+    /// the type, timestamp and first data byte of every tag in a file
+    pub fn tags(file: &[u8]) -> Vec<(u8, u32, u8)> {
+        assert_eq!(&file[..3], b"FLV");
+        let mut tags = Vec::new();
+        let mut pos = HEADER_LEN;
+        while pos < file.len() {
+            let head = &file[pos..pos + TAG_HEADER_LEN];
+            let size = u32::from_be_bytes([0, head[1], head[2], head[3]]) as usize;
+            let ts = u32::from_be_bytes([head[7], head[4], head[5], head[6]]);
+            tags.push((head[0], ts, file[pos + TAG_HEADER_LEN]));
+            let prev = &file[pos + TAG_HEADER_LEN + size..][..4];
+            assert_eq!(
+                u32::from_be_bytes(prev.try_into().unwrap()) as usize,
+                11 + size
+            );
+            pos += TAG_HEADER_LEN + size + 4;
+        }
+        tags
+    }
+
+    fn segments(chunks: Vec<Chunk>) -> Vec<Vec<u8>> {
+        let mut files: Vec<Vec<u8>> = Vec::new();
+        for chunk in chunks {
+            match chunk {
+                Chunk::Start => files.push(Vec::new()),
+                Chunk::Data(d) => files.last_mut().unwrap().extend(d),
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn split_on_keyframes() {
+        let stream = sample(100, 25);
+        let limit = SegmentLimit {
+            size: None,
+            time: Some(Duration::from_millis(1500)),
+        };
+        let mut splitter = FlvSplitter::new(limit);
+        let mut chunks = Vec::new();
+        // feed it in odd sizes so that tags are split across pushes
+        for part in stream.chunks(7) {
+            chunks.extend(splitter.push(part).unwrap());
+        }
+        let files = segments(chunks);
+        // the limit is reached after 1.5s, the next keyframe is at 2s
+        assert_eq!(files.len(), 2);
+        for file in files.iter() {
+            let tags = tags(file);
+            assert_eq!(tags[0], (TAG_SCRIPT, 0, b'o'));
+            assert_eq!(tags[1], (TAG_VIDEO, 0, 0x17));
+            assert_eq!(tags[2], (TAG_AUDIO, 0, 0xaf));
+            assert_eq!(tags[3], (TAG_VIDEO, 0, 0x17));
+        }
+        assert_eq!(tags(&files[0]).len(), 3 + 50 * 2);
+        assert_eq!(tags(&files[1]).last().unwrap().1, 49 * 40);
+    }
+
+    #[test]
+    fn wait_for_keyframe() {
+        let mut stream = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+        stream.extend(tag_bytes(TAG_VIDEO, 0, &[0x17, 0, 0, 0, 0, 1]));
+        stream.extend(tag_bytes(TAG_AUDIO, 5, &[0xaf, 1, 7]));
+        stream.extend(tag_bytes(TAG_VIDEO, 10, &[0x27, 1, 9]));
+        stream.extend(tag_bytes(TAG_VIDEO, 50, &[0x17, 1, 9]));
+        let mut splitter = FlvSplitter::new(SegmentLimit::default());
+        let files = segments(splitter.push(&stream).unwrap());
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            tags(&files[0]),
+            [(TAG_VIDEO, 0, 0x17), (TAG_VIDEO, 0, 0x17)]
+        );
+        assert!(FlvSplitter::new(SegmentLimit::default())
+            .push(b"<html>not a stream</html>")
+            .is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! 直播录制
+
+pub mod danmaku;
+pub mod flv;
+pub mod packet;
+pub mod recorder;
+pub mod room;
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Local;
+use tokio_util::sync::CancellationToken;
+
+use crate::ffmpeg::remux;
+use crate::http::Result;
+use crate::http::{client::Client, url_regex};
+use crate::progress::{error, info};
+use recorder::{RecordOptions, Recorder, Room};
+
+/// how often the room is checked with `--wait`
+const WAIT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 直播录制的选项
+#[derive(Debug, Clone, Copy)]
+pub struct LiveOptions {
+    /// 未开播时等待开播，而不是直接退出
+    pub wait: bool,
+    /// 同时保存弹幕，并为每个分段生成 ASS 字幕
+    pub danmaku: bool,
+    pub record: RecordOptions,
+}
+
+/// record a live room until the stream ends or `token` is cancelled,
+/// then remux every segment to MP4 and write the danmaku received during it next to it
+pub async fn record(
+    client: &Client,
+    url: &str,
+    dir: &Path,
+    options: LiveOptions,
+    token: CancellationToken,
+) -> Result<()> {
+    let room = live_room_id(url)?;
+    let mut info = client.fetch_room_info(room).await?;
+    info!("直播间 {}: {}", info.room_id, info.title);
+    if !info.is_live() {
+        if !options.wait {
+            return Err("直播间未开播，可以使用 --wait 等待开播".into());
+        }
+        info!("直播间未开播，每 {} 秒检查一次", WAIT_INTERVAL.as_secs());
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(WAIT_INTERVAL) => {}
+            }
+            match client.fetch_room_info(room).await {
+                Ok(i) if i.is_live() => {
+                    info = i;
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => error!("获取直播间信息失败: {}", e),
+            }
+        }
+        info!("直播已开始: {}", info.title);
+    }
+
+    fs::create_dir_all(dir)?;
+    let name = format!("{} - {}", info.room_id, info.title.replace('/', "_"));
+    let source = Room {
+        client,
+        room_id: info.room_id,
+    };
+    let danmaku_path = options.danmaku.then(|| {
+        let time = Local::now().format("%Y%m%d-%H%M%S");
+        dir.join(format!("{} - {}.jsonl", name, time))
+    });
+    let recorder = Recorder::new(client, source, dir.to_path_buf(), name, options.record);
+    // the danmaku connection lives as long as the recording
+    let danmaku_token = token.child_token();
+    let (segments, ()) = tokio::join!(
+        async {
+            let segments = recorder.run(&token).await;
+            danmaku_token.cancel();
+            segments
+        },
+        async {
+            let Some(path) = &danmaku_path else {
+                return;
+            };
+            if let Err(e) = danmaku::capture(client, info.room_id, path, &danmaku_token).await {
+                error!("保存弹幕失败: {}", e);
+            }
+        },
+    );
+    let segments = segments?;
+    let events = match &danmaku_path {
+        Some(path) => danmaku::read_events(path).unwrap_or_default(),
+        None => Vec::new(),
+    };
+
+    for segment in segments {
+        if danmaku_path.is_some() {
+            let ass = segment.path.with_extension("ass");
+            match danmaku::write_ass(&events, segment.start, segment.end, &ass) {
+                Ok(0) => {}
+                Ok(n) => info!("已保存 {} 条弹幕: {}", n, ass.display()),
+                Err(e) => error!("保存 {} 失败: {}", ass.display(), e),
+            }
+        }
+        let o_path = segment.path.with_extension("mp4");
+        match remux(&segment.path, &o_path) {
+            Ok(()) => {
+                let _ = fs::remove_file(&segment.path);
+                info!("已保存: {}", o_path.display());
+            }
+            Err(e) => error!("转换 {} 失败，保留原文件: {}", segment.path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+/// the room id in a link like `https://live.bilibili.com/123`, which can be a short id
+fn live_room_id(url: &str) -> Result<i64> {
+    match url_regex(r"^/(?:blanc/)?(\d+)/", url) {
+        Some(id) => Ok(id.parse()?),
+        None => Err("解析直播间号失败".into()),
+    }
+}
+
+#[cfg(test)]
+mod live_test {
+    use super::*;
+
+    #[test]
+    fn room_id() {
+        let url = "https://live.bilibili.com/21452505?spm_id_from=333.1007";
+        assert_eq!(live_room_id(url).unwrap(), 21452505);
+        assert_eq!(
+            live_room_id("https://live.bilibili.com/blanc/6").unwrap(),
+            6
+        );
+        assert!(live_room_id("https://live.bilibili.com/p/html/live-app").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! 直播弹幕 websocket 的数据包格式
+//!
+//! 每个数据包以 16 字节的头部开始，均为大端序：
+//! 包长度 (u32)、头部长度 (u16)、协议版本 (u16)、操作码 (u32)、序号 (u32)。
+//! 协议版本为 2 或 3 时，包体是 zlib 或 brotli 压缩的若干个完整数据包
+
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+use serde_json::Value;
+
+use crate::http::Result;
+
+pub const HEADER_LEN: usize = 16;
+
+/// the body is plain JSON, or the popularity for a heartbeat reply
+const PROTO_RAW: u16 = 1;
+const PROTO_ZLIB: u16 = 2;
+const PROTO_BROTLI: u16 = 3;
+
+pub const OP_HEARTBEAT: u32 = 2;
+const OP_HEARTBEAT_REPLY: u32 = 3;
+const OP_COMMAND: u32 = 5;
+pub const OP_AUTH: u32 = 7;
+const OP_AUTH_REPLY: u32 = 8;
+
+/// 服务器发来的数据包
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    /// 认证的结果，`code` 为 0 时成功
+    AuthReply(Value),
+    /// 心跳的回复，带有直播间的人气值
+    HeartbeatReply(u32),
+    /// 弹幕、礼物等消息，`cmd` 为消息类型
+    Command(Value),
+}
+
+/// a packet sent to the server, with the body uncompressed
+pub fn encode(operation: u32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+    bytes.extend_from_slice(&((HEADER_LEN + body.len()) as u32).to_be_bytes());
+    bytes.extend_from_slice(&(HEADER_LEN as u16).to_be_bytes());
+    bytes.extend_from_slice(&PROTO_RAW.to_be_bytes());
+    bytes.extend_from_slice(&operation.to_be_bytes());
+    bytes.extend_from_slice(&1u32.to_be_bytes());
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// every packet in a websocket message, compressed bodies are unpacked recursively.
+/// Unknown operations are skipped
+pub fn decode(bytes: &[u8]) -> Result<Vec<Packet>> {
+    let mut packets = Vec::new();
+    decode_to(bytes, &mut packets)?;
+    Ok(packets)
+}
+
+fn decode_to(mut bytes: &[u8], packets: &mut Vec<Packet>) -> Result<()> {
+    while !bytes.is_empty() {
+        if bytes.len() < HEADER_LEN {
+            return Err("弹幕数据包不完整".into());
+        }
+        let len = u32::from_be_bytes(bytes[0..4].try_into()?) as usize;
+        let header_len = u16::from_be_bytes(bytes[4..6].try_into()?) as usize;
+        let proto = u16::from_be_bytes(bytes[6..8].try_into()?);
+        let operation = u32::from_be_bytes(bytes[8..12].try_into()?);
+        if len < header_len || header_len < HEADER_LEN || bytes.len() < len {
+            return Err("弹幕数据包不完整".into());
+        }
+        let body = &bytes[header_len..len];
+        bytes = &bytes[len..];
+
+        match (proto, operation) {
+            (PROTO_ZLIB, _) => {
+                let mut data = Vec::new();
+                ZlibDecoder::new(body).read_to_end(&mut data)?;
+                decode_to(&data, packets)?;
+            }
+            (PROTO_BROTLI, _) => {
+                let mut data = Vec::new();
+                brotli::Decompressor::new(body, 4096).read_to_end(&mut data)?;
+                decode_to(&data, packets)?;
+            }
+            (_, OP_HEARTBEAT_REPLY) => {
+                let popularity = body.get(..4).ok_or("弹幕数据包不完整")?;
+                packets.push(Packet::HeartbeatReply(u32::from_be_bytes(
+                    popularity.try_into()?,
+                )));
+            }
+            (_, OP_AUTH_REPLY) => packets.push(Packet::AuthReply(serde_json::from_slice(body)?)),
+            (_, OP_COMMAND) => packets.push(Packet::Command(serde_json::from_slice(body)?)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod packet_test {
+    use super::*;
+    use serde_json::json;
+
+    /// websocket messages as the server sends them: the auth and heartbeat replies,
+    /// a zlib batch of a danmaku and a gift, and a brotli batch of two danmaku and a SuperChat
+    pub const AUTH_REPLY: &[u8] = include_bytes!("testdata/auth_reply.bin");
+    pub const HEARTBEAT_REPLY: &[u8] = include_bytes!("testdata/heartbeat_reply.bin");
+    pub const ZLIB: &[u8] = include_bytes!("testdata/zlib.bin");
+    pub const BROTLI: &[u8] = include_bytes!("testdata/brotli.bin");
+
+    fn cmds(packets: &[Packet]) -> Vec<&str> {
+        packets
+            .iter()
+            .map(|p| match p {
+                Packet::Command(v) => v["cmd"].as_str().unwrap(),
+                _ => panic!("not a command: {:?}", p),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode_header() {
+        let bytes = encode(OP_HEARTBEAT, b"[object Object]");
+        assert_eq!(
+            &bytes[..HEADER_LEN],
+            [0, 0, 0, 31, 0, 16, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1]
+        );
+        assert_eq!(&bytes[HEADER_LEN..], b"[object Object]");
+    }
+
+    #[test]
+    fn decode_replies() {
+        assert_eq!(
+            decode(AUTH_REPLY).unwrap(),
+            [Packet::AuthReply(json!({"code": 0}))]
+        );
+        assert_eq!(
+            decode(HEARTBEAT_REPLY).unwrap(),
+            [Packet::HeartbeatReply(24531)]
+        );
+        // an uncompressed command is decoded the same way
+        let cmd = encode(OP_COMMAND, br#"{"cmd":"LIVE"}"#);
+        assert_eq!(
+            decode(&cmd).unwrap(),
+            [Packet::Command(json!({"cmd": "LIVE"}))]
+        );
+    }
+
+    #[test]
+    fn decode_compressed() {
+        let packets = decode(ZLIB).unwrap();
+        assert_eq!(cmds(&packets), ["DANMU_MSG", "SEND_GIFT"]);
+        let packets = decode(BROTLI).unwrap();
+        assert_eq!(
+            cmds(&packets),
+            ["DANMU_MSG:4:0:2:2:2:0", "SUPER_CHAT_MESSAGE", "DANMU_MSG"]
+        );
+        // a websocket message can hold several packets
+        let joined = [HEARTBEAT_REPLY, ZLIB].concat();
+        assert_eq!(decode(&joined).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn decode_truncated() {
+        assert!(decode(&ZLIB[..10]).is_err());
+        assert!(decode(&BROTLI[..BROTLI.len() - 1]).is_err());
+        let mut bad = ZLIB.to_vec();
+        // a header longer than the packet
+        bad[4..6].copy_from_slice(&1000u16.to_be_bytes());
+        assert!(decode(&bad).is_err());
+    }
+}
@@ -0,0 +1,548 @@
+//! 录制直播流，断开后自动重新连接
+
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Local;
+use reqwest::{header::REFERER, Url};
+use tokio_util::sync::CancellationToken;
+
+use super::flv::{Chunk, FlvSplitter, SegmentLimit};
+use super::room::{PlayUrl, StreamFormat};
+use crate::http::client::Client;
+use crate::http::Result;
+use crate::progress::{error, info};
+
+const REFERER_URL: &str = "https://live.bilibili.com";
+/// consecutive connections without any data before giving up
+const MAX_FAILURES: u32 = 5;
+
+/// 直播流的来源，测试中为本地服务器
+pub trait Source {
+    /// whether the streamer is still live
+    async fn is_live(&self) -> Result<bool>;
+    /// the stream urls to try in order
+    async fn play_urls(&self) -> Result<Vec<PlayUrl>>;
+}
+
+/// bilibili 的直播间
+pub struct Room<'a> {
+    pub client: &'a Client,
+    pub room_id: i64,
+}
+
+impl Source for Room<'_> {
+    async fn is_live(&self) -> Result<bool> {
+        Ok(self.client.fetch_room_info(self.room_id).await?.is_live())
+    }
+
+    async fn play_urls(&self) -> Result<Vec<PlayUrl>> {
+        self.client.fetch_play_urls(self.room_id).await
+    }
+}
+
+/// 录制的分段方式和重连间隔
+#[derive(Debug, Clone, Copy)]
+pub struct RecordOptions {
+    pub limit: SegmentLimit,
+    /// 超过该时间没有收到数据时重新连接
+    pub stall_timeout: Duration,
+    /// 断开后等待多久重新连接
+    pub retry_delay: Duration,
+}
+
+/// 录制完成的分段
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub path: PathBuf,
+    /// 开始和结束录制的时间戳，单位为毫秒
+    pub start: i64,
+    pub end: i64,
+}
+
+/// the segment being written
+struct Output {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    start: i64,
+}
+
+pub struct Recorder<'a, S> {
+    client: &'a Client,
+    source: S,
+    dir: PathBuf,
+    /// the start time of a segment is appended to it to form the file name
+    name: String,
+    options: RecordOptions,
+    output: Option<Output>,
+    segments: Vec<Segment>,
+    /// bytes written in total, to tell whether a connection received anything
+    received: u64,
+}
+
+impl<'a, S: Source> Recorder<'a, S> {
+    pub fn new(
+        client: &'a Client,
+        source: S,
+        dir: PathBuf,
+        name: String,
+        options: RecordOptions,
+    ) -> Self {
+        Self {
+            client,
+            source,
+            dir,
+            name,
+            options,
+            output: None,
+            segments: Vec::new(),
+            received: 0,
+        }
+    }
+
+    /// record until the stream ends or `token` is cancelled, returning the saved segments
+    pub async fn run(mut self, token: &CancellationToken) -> Result<Vec<Segment>> {
+        let mut failures = 0;
+        while !token.is_cancelled() {
+            let received = self.record_once(token).await;
+            self.finish_segment()?;
+            if token.is_cancelled() {
+                break;
+            }
+            match received {
+                Ok(true) => failures = 0,
+                Ok(false) => failures += 1,
+                Err(e) => {
+                    error!("获取直播流失败: {}", e);
+                    failures += 1;
+                }
+            }
+            if failures >= MAX_FAILURES {
+                error!("连续 {} 次没有收到直播数据，停止录制", failures);
+                break;
+            }
+            match self.source.is_live().await {
+                Ok(false) => {
+                    info!("直播已结束");
+                    break;
+                }
+                Ok(true) => {}
+                Err(e) => error!("获取直播状态失败: {}", e),
+            }
+            info!(
+                "直播流已断开，{} 秒后重新连接",
+                self.options.retry_delay.as_secs_f64()
+            );
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep(self.options.retry_delay) => {}
+            }
+        }
+        Ok(self.segments)
+    }
+
+    /// record from the first url that works until it ends, whether any data is received
+    async fn record_once(&mut self, token: &CancellationToken) -> Result<bool> {
+        let urls = self.source.play_urls().await?;
+        for url in urls {
+            let before = self.received;
+            let res = match url.format {
+                StreamFormat::Flv => self.record_flv(&url, token).await,
+                StreamFormat::Ts => self.record_hls(&url, token).await,
+            };
+            if self.received > before {
+                if let Err(e) = res {
+                    error!("直播流中断: {}", e);
+                }
+                return Ok(true);
+            }
+            match res {
+                Ok(()) => return Ok(false),
+                Err(e) => error!("连接直播流 {} 失败: {}", url_host(&url.url), e),
+            }
+        }
+        Ok(false)
+    }
+
+    async fn record_flv(&mut self, url: &PlayUrl, token: &CancellationToken) -> Result<()> {
+        let stall = self.options.stall_timeout;
+        let req = self.client.get_media(&url.url).header(REFERER, REFERER_URL);
+        let Some(resp) = until_stalled(stall, token, self.client.send(req)).await? else {
+            return Ok(());
+        };
+        let mut resp = resp.error_for_status()?;
+        info!("[Live] 开始录制 FLV 直播流 ({})", url.quality);
+        let mut splitter = FlvSplitter::new(self.options.limit);
+        loop {
+            let Some(Some(bytes)) = until_stalled(stall, token, resp.chunk()).await? else {
+                return Ok(());
+            };
+            for chunk in splitter.push(&bytes)? {
+                match chunk {
+                    Chunk::Start => self.start_segment(StreamFormat::Flv)?,
+                    Chunk::Data(data) => self.write(&data)?,
+                }
+            }
+        }
+    }
+
+    /// poll the playlist and append every new MPEG-TS segment, which can simply be joined
+    async fn record_hls(&mut self, play_url: &PlayUrl, token: &CancellationToken) -> Result<()> {
+        let stall = self.options.stall_timeout;
+        let mut url = Url::parse(&play_url.url)?;
+        let mut last_seq: Option<u64> = None;
+        let mut duration = Duration::ZERO;
+        let mut last_data = tokio::time::Instant::now();
+        info!("[Live] 开始录制 HLS 直播流 ({})", play_url.quality);
+        loop {
+            let req = self
+                .client
+                .get_media(url.as_str())
+                .header(REFERER, REFERER_URL);
+            let body = async {
+                self.client
+                    .send(req)
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await
+            };
+            let Some(body) = until_stalled(stall, token, body).await? else {
+                return Ok(());
+            };
+            let playlist = Playlist::parse(&body, &url)?;
+            if let Some(variant) = playlist.variant {
+                url = variant;
+                continue;
+            }
+            for (seq, segment) in playlist.segments {
+                if last_seq.is_some_and(|last| seq <= last) {
+                    continue;
+                }
+                let req = self
+                    .client
+                    .get_media(segment.url.as_str())
+                    .header(REFERER, REFERER_URL);
+                let bytes = async {
+                    self.client
+                        .send(req)
+                        .await?
+                        .error_for_status()?
+                        .bytes()
+                        .await
+                };
+                let Some(bytes) = until_stalled(stall, token, bytes).await? else {
+                    return Ok(());
+                };
+                let size = self.output.as_ref().map(|o| o.size);
+                if size.is_none_or(|s| self.options.limit.reached(s, duration)) {
+                    self.start_segment(StreamFormat::Ts)?;
+                    duration = Duration::ZERO;
+                }
+                self.write(&bytes)?;
+                duration += segment.duration;
+                last_seq = Some(seq);
+                last_data = tokio::time::Instant::now();
+            }
+            if playlist.ended {
+                return Ok(());
+            }
+            if last_data.elapsed() > stall {
+                return Err("直播流超时没有数据".into());
+            }
+            tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(playlist.target_duration / 2) => {}
+            }
+        }
+    }
+
+    fn start_segment(&mut self, format: StreamFormat) -> Result<()> {
+        self.finish_segment()?;
+        let time = Local::now().format("%Y%m%d-%H%M%S");
+        let mut path = self
+            .dir
+            .join(format!("{} - {}.{}", self.name, time, format.extension()));
+        let mut n = 1;
+        while path.exists() {
+            n += 1;
+            path = self.dir.join(format!(
+                "{} - {}-{}.{}",
+                self.name,
+                time,
+                n,
+                format.extension()
+            ));
+        }
+        info!("[Live] 录制到 {}", path.display());
+        let file = File::create(&path)?;
+        self.output = Some(Output {
+            path,
+            file,
+            size: 0,
+            start: Local::now().timestamp_millis(),
+        });
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let output = self.output.as_mut().ok_or("没有正在录制的分段")?;
+        output.file.write_all(data)?;
+        output.size += data.len() as u64;
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// close the current segment, an empty one is removed
+    fn finish_segment(&mut self) -> Result<()> {
+        let Some(mut output) = self.output.take() else {
+            return Ok(());
+        };
+        output.file.flush()?;
+        if output.size == 0 {
+            let _ = fs::remove_file(&output.path);
+        } else {
+            self.segments.push(Segment {
+                path: output.path,
+                start: output.start,
+                end: Local::now().timestamp_millis(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn url_host(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+/// a HLS segment
+#[derive(Debug, PartialEq)]
+struct HlsSegment {
+    url: Url,
+    duration: Duration,
+}
+
+/// the parts of a m3u8 playlist the recorder needs
+#[derive(Debug, Default, PartialEq)]
+struct Playlist {
+    /// the first media playlist of a master playlist
+    variant: Option<Url>,
+    target_duration: Duration,
+    /// segments with their media sequence numbers
+    segments: Vec<(u64, HlsSegment)>,
+    /// `#EXT-X-ENDLIST`, the stream has ended
+    ended: bool,
+}
+
+/// wait for a request of the stream, `None` when the recording is stopped first.
+/// The client has no timeout of its own, so a stalled CDN is given up after `stall`
+async fn until_stalled<T>(
+    stall: Duration,
+    token: &CancellationToken,
+    fut: impl Future<Output = reqwest::Result<T>>,
+) -> Result<Option<T>> {
+    tokio::select! {
+        _ = token.cancelled() => Ok(None),
+        res = tokio::time::timeout(stall, fut) => Ok(Some(res.map_err(|_| "直播流超时没有数据")??)),
+    }
+}
+
+impl Playlist {
+    fn parse(body: &str, base: &Url) -> Result<Self> {
+        if !body.starts_with("#EXTM3U") {
+            return Err("不是 m3u8 播放列表".into());
+        }
+        let mut playlist = Playlist {
+            target_duration: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let mut seq = 0;
+        let mut duration = Duration::ZERO;
+        let mut is_variant = false;
+        for line in body.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(v) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+                playlist.target_duration = Duration::from_secs_f64(v.parse().unwrap_or(1.0));
+            } else if let Some(v) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                seq = v.parse()?;
+            } else if let Some(v) = line.strip_prefix("#EXTINF:") {
+                let v = v.split(',').next().unwrap_or_default();
+                duration = Duration::from_secs_f64(v.parse().unwrap_or(0.0));
+            } else if line.starts_with("#EXT-X-STREAM-INF") {
+                is_variant = true;
+            } else if line.starts_with("#EXT-X-MAP") {
+                return Err("不支持 fMP4 格式的 HLS 直播流".into());
+            } else if line == "#EXT-X-ENDLIST" {
+                playlist.ended = true;
+            } else if !line.starts_with('#') {
+                let url = base.join(line)?;
+                if is_variant {
+                    playlist.variant = Some(url);
+                    return Ok(playlist);
+                }
+                playlist.segments.push((seq, HlsSegment { url, duration }));
+                seq += 1;
+            }
+        }
+        Ok(playlist)
+    }
+}
+
+#[cfg(test)]
+mod recorder_test {
+    use super::*;
+    use crate::http::retry::RetryPolicy;
+    use crate::http::test_server::{serve, temp_dir, Behaviour};
+    use crate::live::flv::flv_test::{sample, tags};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::OnceLock;
+
+    /// a stand-in live room serving a recorded stream, live for the first `live` checks
+    struct LocalSource {
+        url: String,
+        live: u32,
+        checks: AtomicU32,
+    }
+
+    impl Source for LocalSource {
+        async fn is_live(&self) -> Result<bool> {
+            Ok(self.checks.fetch_add(1, Ordering::SeqCst) < self.live)
+        }
+
+        async fn play_urls(&self) -> Result<Vec<PlayUrl>> {
+            Ok(vec![PlayUrl {
+                format: StreamFormat::Flv,
+                url: self.url.clone(),
+                quality: "原画".into(),
+            }])
+        }
+    }
+
+    fn stream() -> &'static [u8] {
+        static STREAM: OnceLock<Vec<u8>> = OnceLock::new();
+        STREAM.get_or_init(|| sample(100, 25))
+    }
+
+    fn options() -> RecordOptions {
+        RecordOptions {
+            limit: SegmentLimit::default(),
+            stall_timeout: Duration::from_secs(5),
+            retry_delay: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn record_local_flv() {
+        let source = LocalSource {
+            url: serve(stream(), Behaviour::Ok).await,
+            live: 0,
+            checks: AtomicU32::new(0),
+        };
+        let client = Client::new();
+        let dir = temp_dir("live-flv");
+        let mut options = options();
+        options.limit.time = Some(Duration::from_millis(1500));
+        let recorder = Recorder::new(&client, source, dir.clone(), "直播".into(), options);
+        let segments = recorder.run(&CancellationToken::new()).await.unwrap();
+        assert_eq!(segments.len(), 2);
+        for segment in segments.iter() {
+            let tags = tags(&fs::read(&segment.path).unwrap());
+            assert!(segment.start <= segment.end);
+            assert_eq!(tags[3], (9, 0, 0x17));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_drop() {
+        // every connection is cut after half of the stream, the room stays live for one check
+        let source = LocalSource {
+            url: serve(stream(), Behaviour::Truncate(stream().len() / 2)).await,
+            live: 1,
+            checks: AtomicU32::new(0),
+        };
+        let mut client = Client::new();
+        client.set_retry(RetryPolicy {
+            retries: 0,
+            ..Default::default()
+        });
+        let dir = temp_dir("live-reconnect");
+        let recorder = Recorder::new(&client, source, dir.clone(), "直播".into(), options());
+        let segments = recorder.run(&CancellationToken::new()).await.unwrap();
+        // one segment per connection
+        assert_eq!(segments.len(), 2);
+        assert_ne!(segments[0].path, segments[1].path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_playlist() {
+        // the headers arrive but the playlist never does
+        let url = serve(b"#EXTM3U\n", Behaviour::Stall).await;
+        let source = LocalSource {
+            url: url.clone(),
+            live: 0,
+            checks: AtomicU32::new(0),
+        };
+        let client = Client::new();
+        let dir = temp_dir("live-stall");
+        let mut options = options();
+        options.stall_timeout = Duration::from_millis(200);
+        let play_url = PlayUrl {
+            format: StreamFormat::Ts,
+            url,
+            quality: "原画".into(),
+        };
+        let mut recorder = Recorder::new(&client, source, dir.clone(), "直播".into(), options);
+        let token = CancellationToken::new();
+        assert!(recorder.record_hls(&play_url, &token).await.is_err());
+        // and stopping the recording does not wait for it
+        token.cancel();
+        assert!(recorder.record_hls(&play_url, &token).await.is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_playlist() {
+        let base = Url::parse("https://a.example.com/live/index.m3u8?t=1").unwrap();
+        let body = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:7\n\
+                    #EXTINF:1.5,\n7.ts\n#EXTINF:2.0,\nhttps://b.example.com/8.ts\n";
+        let playlist = Playlist::parse(body, &base).unwrap();
+        assert_eq!(playlist.target_duration, Duration::from_secs(2));
+        assert!(!playlist.ended);
+        assert_eq!(
+            playlist.segments,
+            [
+                (
+                    7,
+                    HlsSegment {
+                        url: Url::parse("https://a.example.com/live/7.ts").unwrap(),
+                        duration: Duration::from_millis(1500),
+                    }
+                ),
+                (
+                    8,
+                    HlsSegment {
+                        url: Url::parse("https://b.example.com/8.ts").unwrap(),
+                        duration: Duration::from_secs(2),
+                    }
+                ),
+            ]
+        );
+
+        let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nhigh/index.m3u8\n";
+        let playlist = Playlist::parse(master, &base).unwrap();
+        assert_eq!(
+            playlist.variant.unwrap().as_str(),
+            "https://a.example.com/live/high/index.m3u8"
+        );
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-MAP:URI=\"h.m4s\"\n", &base).is_err());
+    }
+}
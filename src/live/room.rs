@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::http::Result;
+use crate::http::{check_code, client::Client};
+use crate::progress::info;
+
+/// 直播间信息
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomInfo {
+    /// 真实房间号，链接中的可能是短号
+    pub room_id: i64,
+    pub uid: i64,
+    pub title: String,
+    /// 1 为直播中，0 为未开播，2 为轮播
+    pub live_status: i32,
+}
+
+impl RoomInfo {
+    pub fn is_live(&self) -> bool {
+        self.live_status == 1
+    }
+}
+
+/// 直播流的封装格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// HTTP-FLV
+    Flv,
+    /// HLS 的 MPEG-TS 分片
+    Ts,
+}
+
+impl StreamFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StreamFormat::Flv => "flv",
+            StreamFormat::Ts => "ts",
+        }
+    }
+}
+
+/// 一个直播流链接
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayUrl {
+    pub format: StreamFormat,
+    pub url: String,
+    /// 如 `原画`
+    pub quality: String,
+}
+
+impl Client {
+    /// the room info from a room id which can be a short id
+    pub async fn fetch_room_info(&self, room: i64) -> Result<RoomInfo> {
+        let url = format!(
+            "https://api.live.bilibili.com/room/v1/Room/get_info?room_id={}",
+            room
+        );
+        let mut resp: Value = self.send(self.get(&url)).await?.json().await?;
+        check_code(&resp)?;
+        Ok(serde_json::from_value(resp["data"].take())?)
+    }
+
+    /// the FLV and HLS urls of the stream in the best quality, FLV first
+    pub async fn fetch_play_urls(&self, room_id: i64) -> Result<Vec<PlayUrl>> {
+        info!("[Fetch] 获取直播流地址");
+        let url = format!(
+            "https://api.live.bilibili.com/xlive/web-room/v2/index/getRoomPlayInfo?room_id={}&protocol=0,1&format=0,1&codec=0,1&qn=10000&platform=web&ptype=8",
+            room_id
+        );
+        let resp: Value = self.send(self.get(&url)).await?.json().await?;
+        check_code(&resp)?;
+        let urls = play_urls(&resp["data"]);
+        if urls.is_empty() {
+            return Err("未找到直播流".into());
+        }
+        Ok(urls)
+    }
+}
+
+/// every url in `playurl_info`, FLV before HLS and AVC before HEVC,
+/// as HEVC FLV is not supported by many players
+fn play_urls(data: &Value) -> Vec<PlayUrl> {
+    let playurl = &data["playurl_info"]["playurl"];
+    let quality = |qn: i64| {
+        playurl["g_qn_desc"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|d| d["qn"].as_i64() == Some(qn))
+            .and_then(|d| d["desc"].as_str())
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let mut urls = Vec::new();
+    for stream in playurl["stream"].as_array().into_iter().flatten() {
+        for format in stream["format"].as_array().into_iter().flatten() {
+            let format_type = match format["format_name"].as_str() {
+                Some("flv") => StreamFormat::Flv,
+                Some("ts") => StreamFormat::Ts,
+                // fMP4 needs its init segment, the other formats cover the same streams
+                _ => continue,
+            };
+            for codec in format["codec"].as_array().into_iter().flatten() {
+                let base_url = codec["base_url"].as_str().unwrap_or_default();
+                let hevc = codec["codec_name"].as_str() == Some("hevc");
+                for info in codec["url_info"].as_array().into_iter().flatten() {
+                    let host = info["host"].as_str().unwrap_or_default();
+                    let extra = info["extra"].as_str().unwrap_or_default();
+                    let url = PlayUrl {
+                        format: format_type,
+                        url: format!("{}{}{}", host, base_url, extra),
+                        quality: quality(codec["current_qn"].as_i64().unwrap_or_default()),
+                    };
+                    urls.push((hevc, url));
+                }
+            }
+        }
+    }
+    urls.sort_by_key(|(hevc, url)| (url.format != StreamFormat::Flv, *hevc));
+    urls.into_iter().map(|(_, url)| url).collect()
+}
+
+#[cfg(test)]
+mod room_test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_play_urls() {
+        let codec = |name: &str, base: &str| {
+            json!({
+                "codec_name": name, "current_qn": 10000, "base_url": base,
+                "url_info": [{"host": "https://a.example.com", "extra": "?t=1"}],
+            })
+        };
+        let data = json!({
+            "playurl_info": {"playurl": {
+                "g_qn_desc": [{"qn": 10000, "desc": "原画"}],
+                "stream": [
+                    {"protocol_name": "http_hls", "format": [
+                        {"format_name": "ts", "codec": [codec("avc", "/live/1.m3u8")]},
+                        {"format_name": "fmp4", "codec": [codec("avc", "/live/2.m3u8")]},
+                    ]},
+                    {"protocol_name": "http_stream", "format": [
+                        {"format_name": "flv", "codec": [codec("hevc", "/live/3.flv"), codec("avc", "/live/4.flv")]},
+                    ]},
+                ],
+            }}
+        });
+        let urls = play_urls(&data);
+        let got: Vec<_> = urls.iter().map(|u| (u.format, u.url.as_str())).collect();
+        assert_eq!(
+            got,
+            [
+                (StreamFormat::Flv, "https://a.example.com/live/4.flv?t=1"),
+                (StreamFormat::Flv, "https://a.example.com/live/3.flv?t=1"),
+                (StreamFormat::Ts, "https://a.example.com/live/1.m3u8?t=1"),
+            ]
+        );
+        assert_eq!(urls[0].quality, "原画");
+    }
+}
@@ -1,73 +1,220 @@
+mod archive;
+mod audio;
 mod bangumi;
+mod chapter;
+mod cheese;
 mod cli;
 mod ffmpeg;
 mod http;
+mod live;
+mod nfo;
 mod parser;
+mod progress;
 mod tui;
+mod workdir;
 
+use archive::Archive;
+use bangumi::BangumiOptions;
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, Command};
 use http::{
+    cdn::CdnConfig,
     client::Client,
-    download::DownloadTask,
-    fetch::{process_url, VideoType},
+    download::{DownloadOptions, DownloadTask, Summary, Task},
+    fetch::{process_url, Metadata, VideoType},
+    limit::RateLimiter,
+    retry::RetryPolicy,
 };
-use std::sync::Arc;
-use tui::{select_download_video, wait, SelectionUI};
+use live::{flv::SegmentLimit, recorder::RecordOptions, LiveOptions};
+use parser::{AudioStream, DubChoice, MediaInfo};
+use progress::{info, ProgressMode};
+use serde::Serialize;
+use std::{
+    io::{stdout, IsTerminal},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+use tui::{auto_selection, select_download_video, wait, Dashboard, SelectionUI};
+use workdir::WorkDir;
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    if let Some(Command::Cleanup { temp_dir }) = cli.command {
+        let base = temp_dir.unwrap_or_else(WorkDir::default_base);
+        match workdir::cleanup(&base) {
+            Ok(n) => println!("已删除 {} 个残留的临时文件夹", n),
+            Err(e) => {
+                eprintln!("清理临时文件失败: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    progress::set_mode(if cli.dump_json {
+        ProgressMode::Json
+    } else {
+        cli.progress
+    });
     let mut dir = cli.dl_dir;
-    let client = Client::new();
+    let api_proxy = cli.api_proxy.as_deref().or(cli.proxy.as_deref());
+    let media_proxy = cli.media_proxy.as_deref().or(cli.proxy.as_deref());
+    let mut client = Client::new();
+    if api_proxy.is_some() || media_proxy.is_some() {
+        client
+            .set_proxy(api_proxy, media_proxy)
+            .expect("代理地址无效");
+    }
+    client.set_retry(RetryPolicy {
+        retries: cli.retries,
+        ..Default::default()
+    });
 
     if let Some(c) = cli.cookies {
-        println!("添加 cookies");
+        info!("添加 cookies");
         client.add_cookies(&c);
     };
 
-    let _ = client.validate_login().await;
+    let login = client.login_status().await.unwrap_or_default();
+    let client = Arc::new(client);
+
+    let url = cli.url.as_ref().expect("缺少视频链接").as_str();
+    let archive = cli
+        .download_archive
+        .map(|p| Archive::open(p).expect("读取下载记录失败"));
+
+    let res = match process_url(url) {
+        VideoType::Bangumi => {
+            let options = BangumiOptions {
+                all_seasons: cli.all_seasons,
+                media_server: cli.media_server,
+                access: cli.access,
+            };
+            client.get_bangumi(url, &mut dir, options).await
+        }
+        VideoType::Course => client.get_course(url, &mut dir).await,
+        VideoType::Audio => client.get_audio(url, &mut dir).await,
+        VideoType::Video => client.get_video(url).await,
+        VideoType::Live => {
+            let options = LiveOptions {
+                wait: cli.wait,
+                danmaku: cli.danmaku,
+                record: RecordOptions {
+                    limit: SegmentLimit {
+                        size: cli.segment_size,
+                        time: cli.segment_time.map(Duration::from_secs),
+                    },
+                    stall_timeout: Duration::from_secs(cli.stall_timeout),
+                    retry_delay: Duration::from_secs(3),
+                },
+            };
+            record_live(&client, url, &dir, options).await;
+            return;
+        }
+    };
+    let (metadata, mut video_list) = match res {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("获取视频信息失败: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(a) = &archive {
+        video_list
+            .iter_mut()
+            .for_each(|v| v.archived = a.contains(&v.key));
+    }
 
-    let url = cli.url.as_str();
-    let video_list = match process_url(url) {
-        VideoType::Bangumi => client
-            .get_bangumi(url, &mut dir)
-            .await
-            .expect("获取番剧列表失败"),
-        VideoType::Video => client.get_video(url).await.expect("获取视频失败"),
+    let dub = DubChoice {
+        lang: cli.audio_lang,
+        all: cli.all_dubs,
     };
+    if cli.dump_json {
+        let res = auto_selection(&video_list, cli.items.as_ref());
+        dump_json(&client, metadata, &video_list, &res, &dub).await;
+        return;
+    }
 
-    wait();
-    let mut sui = SelectionUI::new(&video_list);
-    sui.run().expect("Failed to run tui");
-    let res = sui.get_selection();
+    // 没有终端时无法显示选择界面，自动切换为非交互模式
+    let interactive = !cli.yes && cli.items.is_none() && stdout().is_terminal();
+    let res = if interactive {
+        wait();
+        let mut sui = SelectionUI::new(&video_list)
+            .vip(login.vip)
+            .fetch_formats(Arc::clone(&client));
+        progress::set_fullscreen(true);
+        sui.run().expect("Failed to run tui");
+        progress::set_fullscreen(false);
+        sui.get_selection()
+    } else {
+        auto_selection(&video_list, cli.items.as_ref())
+    };
     let selected_video_list = select_download_video(video_list, res);
 
     if selected_video_list.is_empty() {
         return;
     }
 
-    let dl = Arc::new(DownloadTask::new(dir, client, selected_video_list));
-    let listen_task = tokio::spawn(listen_for_interrupt());
+    let options = DownloadOptions {
+        archive,
+        cdn: CdnConfig {
+            prefer: cli.prefer_cdn,
+            host: cli.cdn_host,
+        },
+        stall_timeout: Duration::from_secs(cli.stall_timeout),
+        connections: cli.connections.into(),
+        limiter: (cli.limit_rate.is_some() || cli.limit_schedule.is_some()).then(|| {
+            RateLimiter::new(
+                cli.limit_rate.filter(|&r| r > 0),
+                cli.limit_schedule.unwrap_or_default(),
+            )
+        }),
+        overwrite: cli.overwrite,
+        cut_op_ed: cli.cut_op_ed,
+        dub,
+    };
+    let work_dir = WorkDir::create(cli.temp_dir.unwrap_or_else(WorkDir::default_base))
+        .expect("临时文件夹新建失败");
+    // 交互模式下用下载面板代替进度条
+    let dashboard = interactive && progress::mode() == ProgressMode::Bar;
+    let mut dl = DownloadTask::new(dir, work_dir, client, selected_video_list, options);
+    dl.keep_alive = dashboard;
+    let dl = Arc::new(dl);
+    let token = CancellationToken::new();
+    tokio::spawn(listen_for_interrupt(token.clone()));
 
-    let clone_dl = Arc::clone(&dl);
-    let download_task = tokio::spawn(async move {
-        clone_dl.execute().await;
+    let done = CancellationToken::new();
+    let ui = dashboard.then(|| {
+        progress::set_fullscreen(true);
+        let (dl, token, done) = (Arc::clone(&dl), token.clone(), done.clone());
+        tokio::task::spawn_blocking(move || {
+            let res = Dashboard::new(&dl, token.clone(), done).run();
+            if res.is_err() {
+                // nobody can manage the queue anymore
+                token.cancel();
+            }
+            res
+        })
     });
 
-    tokio::select! {
-        _ = listen_task => {
-            println!("task canceled by user");
+    let clone_dl = Arc::clone(&dl);
+    let download_task = tokio::spawn(async move { clone_dl.execute(token).await });
+
+    let res = download_task.await;
+    done.cancel();
+    if let Some(ui) = ui {
+        if let Ok(Err(e)) = ui.await {
+            eprintln!("Failed to run tui: {}", e);
         }
-        res = download_task => {
-            match res {
-                Ok(_) => {
-                    println!("Completed");
-                },
-                Err(e) => {
-                    eprintln!("panicked: {:?}", e)
-                }
-            }
+        progress::set_fullscreen(false);
+    }
+    match res {
+        Ok(summary) => print_summary(&summary),
+        Err(e) => {
+            eprintln!("panicked: {:?}", e)
         }
     }
 
@@ -75,8 +222,108 @@ async fn main() {
     dl.remove_tmp_file();
 }
 
-async fn listen_for_interrupt() {
+/// record a live room instead of downloading, Ctrl-C stops the recording and keeps it
+async fn record_live(client: &Client, url: &str, dir: &Path, options: LiveOptions) {
+    let token = CancellationToken::new();
+    tokio::spawn(listen_for_interrupt(token.clone()));
+    if let Err(e) = live::record(client, url, dir, options, token).await {
+        eprintln!("录制直播失败: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn print_summary(summary: &Summary) {
+    if summary.interrupted == 0 && summary.remaining == 0 {
+        info!(
+            "Completed: 成功 {}，跳过 {}，失败 {}，取消 {}",
+            summary.finished, summary.skipped, summary.failed, summary.cancelled
+        );
+    } else {
+        info!(
+            "已停止: 成功 {}，跳过 {}，失败 {}，取消 {}，中断 {}，未开始 {}",
+            summary.finished,
+            summary.skipped,
+            summary.failed,
+            summary.cancelled,
+            summary.interrupted,
+            summary.remaining
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct TaskDump<'a> {
+    number: usize,
+    key: &'a str,
+    title: &'a str,
+    link: &'a str,
+    archived: bool,
+    selected: bool,
+    video: Option<MediaInfo>,
+    audio: Option<AudioStream>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    dubs: Vec<AudioStream>,
+}
+
+#[derive(Serialize)]
+struct Dump<'a> {
+    info: Metadata,
+    tasks: Vec<TaskDump<'a>>,
+}
+
+/// 输出视频信息、任务列表和选中任务将要下载的格式
+async fn dump_json(
+    client: &Client,
+    info: Metadata,
+    video_list: &[Task],
+    selection: &[bool],
+    dub: &DubChoice,
+) {
+    let mut tasks = Vec::new();
+    for (task, &selected) in video_list.iter().zip(selection) {
+        // songs have a single stream and no formats to show
+        let streams = if selected && task.song.is_none() {
+            match client.fetch_streams(&task.link, &task.format(), dub).await {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    eprintln!("获取视频: {} 播放信息失败: {}", task.title, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let (video, audio, dubs) = match streams {
+            Some(s) => (Some(s.video), Some(s.audio), s.dubs),
+            None => (None, None, Vec::new()),
+        };
+        tasks.push(TaskDump {
+            number: task.number,
+            key: &task.key,
+            title: &task.title,
+            link: &task.link,
+            archived: task.archived,
+            selected,
+            video,
+            audio,
+            dubs,
+        });
+    }
+    let dump = Dump { info, tasks };
+    println!("{}", serde_json::to_string_pretty(&dump).unwrap());
+}
+
+/// the first Ctrl-C stops the downloads gracefully, the second one exits immediately
+async fn listen_for_interrupt(token: CancellationToken) {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to listen for ctrl-c event");
+    info!("正在停止下载并保存进度，再次按 Ctrl-C 立即退出");
+    token.cancel();
+
     tokio::signal::ctrl_c()
         .await
         .expect("failed to listen for ctrl-c event");
+    eprintln!("task canceled by user");
+    std::process::exit(130);
 }
@@ -0,0 +1,83 @@
+//! Jellyfin、Kodi 等媒体服务器读取的 NFO 元数据
+
+/// 剧集的 `tvshow.nfo`
+pub fn tvshow(title: &str, plot: &str, premiered: Option<&str>) -> String {
+    document(
+        "tvshow",
+        &[
+            ("title", Some(title)),
+            ("plot", Some(plot)),
+            ("premiered", premiered),
+        ],
+    )
+}
+
+/// 季度文件夹中的 `season.nfo`
+pub fn season(title: &str, number: u32, plot: &str) -> String {
+    document(
+        "season",
+        &[
+            ("title", Some(title)),
+            ("seasonnumber", Some(&number.to_string())),
+            ("plot", Some(plot)),
+        ],
+    )
+}
+
+/// 与视频同名的单集 NFO
+pub fn episode(title: &str, show: &str, season: u32, episode: u32, aired: Option<&str>) -> String {
+    document(
+        "episodedetails",
+        &[
+            ("title", Some(title)),
+            ("showtitle", Some(show)),
+            ("season", Some(&season.to_string())),
+            ("episode", Some(&episode.to_string())),
+            ("aired", aired),
+        ],
+    )
+}
+
+/// an xml document with a child element for every field, empty fields are left out
+fn document(root: &str, fields: &[(&str, Option<&str>)]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+    xml.push_str(&format!("<{}>\n", root));
+    for (name, value) in fields {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            xml.push_str(&format!("  <{0}>{1}</{0}>\n", name, escape(value)));
+        }
+    }
+    xml.push_str(&format!("</{}>\n", root));
+    xml
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod nfo_test {
+    use super::*;
+
+    #[test]
+    fn episode_nfo() {
+        let nfo = episode("A & <B>", "番剧", 1, 3, None);
+        assert_eq!(
+            nfo,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+             <episodedetails>\n  \
+             <title>A &amp; &lt;B&gt;</title>\n  \
+             <showtitle>番剧</showtitle>\n  \
+             <season>1</season>\n  \
+             <episode>3</episode>\n\
+             </episodedetails>\n"
+        );
+        assert!(
+            tvshow("番剧", "", Some("2024-01-01")).contains("<premiered>2024-01-01</premiered>")
+        );
+    }
+}
@@ -1,15 +1,17 @@
-use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
-use std::{error::Error, path::Path};
+use std::error::Error;
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::http::check_code;
+use crate::progress::info;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct SegmentBase {
     initialization: String,
     index_range: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MediaInfo {
     id: i32,
     pub base_url: String,
@@ -22,9 +24,48 @@ pub struct MediaInfo {
     segment_base: SegmentBase,
 }
 
+impl MediaInfo {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// bits per second
+    pub fn bandwidth(&self) -> u64 {
+        self.bandwidth as u64
+    }
+
+    /// whether this stream has a higher quality than an existing video, compared by the quality
+    /// id written when it was merged, or by the height for a video without one
+    pub fn is_better_than(&self, height: i32, quality: Option<i32>) -> bool {
+        match quality {
+            Some(id) => self.id > id,
+            None => self.height > height,
+        }
+    }
+
+    /// a short description like `1080p avc1` for video or `192kbps` for audio
+    pub fn quality(&self) -> String {
+        let codec = self.codecs.split('.').next().unwrap_or_default();
+        if self.height > 0 {
+            format!("{}p {}", self.height, codec)
+        } else {
+            format!("{}kbps", self.bandwidth / 1000)
+        }
+    }
+
+    pub fn codecs(&self) -> &str {
+        &self.codecs
+    }
+
+    /// `base_url` followed by every `backup_url`
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.base_url.as_str()).chain(self.backup_url.iter().map(String::as_str))
+    }
+}
+
 type MediaInfoOption = Option<MediaInfo>;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct Dolby {
     #[serde(rename = "type")]
     type_: i32,
@@ -39,17 +80,77 @@ struct Flac {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Dash {
+    /// 时长，单位为秒
+    #[serde(default)]
+    duration: u64,
     audio: Vec<MediaInfoOption>,
     pub video: Vec<MediaInfoOption>,
+    #[serde(default)]
     dolby: Dolby,
     flac: Option<Flac>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PlayInfo {
+    #[serde(default)]
+    accept_quality: Vec<i32>,
+    #[serde(default)]
     accept_description: Vec<String>,
+    #[serde(default)]
     accept_format: String,
     pub dash: Dash,
+    /// 番剧的多语言配音
+    #[serde(default)]
+    language: Option<LanguageList>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct LanguageList {
+    #[serde(default)]
+    items: Vec<LanguageItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LanguageItem {
+    #[serde(flatten)]
+    language: Language,
+    /// 为空时表示 `dash.audio` 中的默认音轨
+    #[serde(default)]
+    audio: Option<Vec<MediaInfoOption>>,
+}
+
+/// 一种配音的语言，如 `{ lang: "yue", title: "粤语" }`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Language {
+    pub lang: String,
+    #[serde(default)]
+    pub title: String,
+}
+
+impl Language {
+    /// whether `name` is the language code (case insensitive) or the title of this language
+    pub fn matches(&self, name: &str) -> bool {
+        self.lang.eq_ignore_ascii_case(name) || self.title == name
+    }
+
+    /// the ISO 639-2 code written as the language tag of an audio track, like `jpn`,
+    /// codes without a known mapping are kept as they are
+    pub fn iso639(&self) -> &str {
+        const CODES: [(&str, &str); 8] = [
+            ("ja", "jpn"),
+            ("jp", "jpn"),
+            ("zh", "chi"),
+            ("cn", "chi"),
+            ("zh-hans", "chi"),
+            ("zh-hant", "chi"),
+            ("en", "eng"),
+            ("ko", "kor"),
+        ];
+        CODES
+            .iter()
+            .find(|(code, _)| self.lang.eq_ignore_ascii_case(code))
+            .map_or(&self.lang, |(_, iso)| iso)
+    }
 }
 
 fn find_start_token<'a>(
@@ -66,7 +167,7 @@ fn find_start_token<'a>(
 }
 
 pub fn extract_play_info(body: String) -> Result<PlayInfo, Box<dyn Error>> {
-    println!("[Parsing] 解析视频链接......");
+    info!("[Parsing] 解析视频链接......");
     let start_tokens = ["<script>window.__playinfo__=", "const playurlSSRData = "];
     let end_tokens = ["</script>", "if"];
 
@@ -79,6 +180,7 @@ pub fn extract_play_info(body: String) -> Result<PlayInfo, Box<dyn Error>> {
     let start_index = start.unwrap() + start_tokens[index].len();
     let end_index = body[start_index..].find(end_token).unwrap() + start_index;
     let mut serde_res = serde_json::from_str::<Value>(&body[start_index..end_index])?;
+    check_code(&serde_res)?;
     // print!("{:#?}", serde_res);
     if index == 0 {
         let play_info: PlayInfo = serde_json::from_value(serde_res["data"].take())?;
@@ -92,56 +194,191 @@ pub fn extract_play_info(body: String) -> Result<PlayInfo, Box<dyn Error>> {
     Err("failed to extract play info".into())
 }
 
-pub fn choose_audio_stream(play_info: &mut PlayInfo) -> MediaInfoOption {
-    let mut audio_data = Vec::new();
-    for audio in &mut play_info.dash.audio {
-        audio_data.push(audio);
-    }
+/// 为单个视频选择的音视频流，`None` 表示使用码率最高的流
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormatChoice {
+    /// quality id and codecs of the video stream
+    pub video: Option<(i32, String)>,
+    /// quality id of the audio stream
+    pub audio: Option<i32>,
+}
 
-    let dolby = play_info.dash.dolby.audio.as_mut();
-    if dolby.is_some() {
-        let dolby_audio = dolby.unwrap();
-        for audio in dolby_audio {
-            audio_data.push(audio);
+/// 番剧配音的选择
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DubChoice {
+    /// 用作主音轨的配音，语言代码或名称，找不到时使用默认音轨
+    pub lang: Option<String>,
+    /// 将其他配音也作为带语言标签的音轨混流
+    pub all: bool,
+}
+
+/// 默认音轨以外的一种配音
+#[derive(Debug, Clone)]
+pub struct Dub {
+    pub language: Language,
+    /// 按码率从高到低排序
+    pub audio: Vec<MediaInfo>,
+}
+
+/// 一条将要下载的音频流和它的语言
+#[derive(Serialize, Debug, Clone)]
+pub struct AudioStream {
+    /// `None` if the play info does not tell the language
+    pub language: Option<Language>,
+    #[serde(flatten)]
+    pub info: MediaInfo,
+}
+
+/// 一个视频将要下载的音视频流
+#[derive(Debug, Clone)]
+pub struct Streams {
+    pub video: MediaInfo,
+    /// 主音轨
+    pub audio: AudioStream,
+    /// 额外混流的其他配音，没有选择全部配音时为空
+    pub dubs: Vec<AudioStream>,
+}
+
+/// 一个视频可以下载的全部音视频流
+#[derive(Debug, Clone)]
+pub struct Formats {
+    /// 时长，单位为秒
+    pub duration: u64,
+    /// 按码率从高到低排序
+    pub video: Vec<MediaInfo>,
+    /// 按码率从高到低排序，包括杜比和 Hi-Res 音频
+    pub audio: Vec<MediaInfo>,
+    /// 默认音轨 `audio` 的语言
+    pub language: Option<Language>,
+    /// 其他语言的配音
+    pub dubs: Vec<Dub>,
+    /// quality id and its name like `1080P 高清`
+    names: Vec<(i32, String)>,
+}
+
+impl Formats {
+    pub fn new(play_info: PlayInfo) -> Self {
+        let dash = play_info.dash;
+        let mut video: Vec<MediaInfo> = dash.video.into_iter().flatten().collect();
+        let mut audio: Vec<MediaInfo> = dash.audio.into_iter().flatten().collect();
+        audio.extend(dash.dolby.audio.into_iter().flatten().flatten());
+        audio.extend(dash.flac.and_then(|f| f.audio).flatten());
+        video.sort_by_key(|m| std::cmp::Reverse(m.bandwidth));
+        audio.sort_by_key(|m| std::cmp::Reverse(m.bandwidth));
+
+        let mut language = None;
+        let mut dubs = Vec::new();
+        for item in play_info.language.unwrap_or_default().items {
+            let mut dub: Vec<MediaInfo> = item.audio.into_iter().flatten().flatten().collect();
+            if dub.is_empty() {
+                language.get_or_insert(item.language);
+                continue;
+            }
+            dub.sort_by_key(|m| std::cmp::Reverse(m.bandwidth));
+            dubs.push(Dub {
+                language: item.language,
+                audio: dub,
+            });
         }
+        Self {
+            duration: dash.duration,
+            video,
+            audio,
+            language,
+            dubs,
+            names: play_info
+                .accept_quality
+                .into_iter()
+                .zip(play_info.accept_description)
+                .collect(),
+        }
+    }
+
+    /// the streams with the highest bandwidth
+    pub fn best(&self) -> Option<(&MediaInfo, &MediaInfo)> {
+        Some((self.video.first()?, self.audio.first()?))
+    }
+
+    /// the chosen streams, falling back to the best ones if a chosen stream is not available
+    pub fn pick(&self, choice: &FormatChoice) -> Option<(&MediaInfo, &MediaInfo)> {
+        let (best_video, best_audio) = self.best()?;
+        let video = choice
+            .video
+            .as_ref()
+            .and_then(|(id, codecs)| {
+                self.video
+                    .iter()
+                    .find(|v| v.id == *id && v.codecs == *codecs)
+            })
+            .unwrap_or(best_video);
+        let audio = choice
+            .audio
+            .and_then(|id| self.audio.iter().find(|a| a.id == id))
+            .unwrap_or(best_audio);
+        Some((video, audio))
     }
 
-    let flac = play_info.dash.flac.as_mut();
-    if flac.is_some() {
-        if let Some(f) = flac {
-            let a = &mut f.audio;
-            a.is_some().then(|| audio_data.push(a.as_mut().unwrap()));
+    /// the chosen streams with the audio in the chosen language, and every other dub
+    /// after it if `dub.all` is set. The default audio is used if no dub matches
+    pub fn streams(&self, choice: &FormatChoice, dub: &DubChoice) -> Option<Streams> {
+        let (video, audio) = self.pick(choice)?;
+        let mut tracks: Vec<AudioStream> = std::iter::once(AudioStream {
+            language: self.language.clone(),
+            info: audio.clone(),
+        })
+        .chain(self.dubs.iter().filter_map(|d| {
+            // the chosen audio quality if the dub has it, else its best one
+            let info = choice
+                .audio
+                .and_then(|id| d.audio.iter().find(|a| a.id() == id))
+                .or(d.audio.first())?;
+            Some(AudioStream {
+                language: Some(d.language.clone()),
+                info: info.clone(),
+            })
+        }))
+        .collect();
+        let main = dub
+            .lang
+            .as_ref()
+            .and_then(|lang| {
+                tracks
+                    .iter()
+                    .position(|t| t.language.as_ref().is_some_and(|l| l.matches(lang)))
+            })
+            .unwrap_or(0);
+        let audio = tracks.remove(main);
+        if !dub.all {
+            tracks.clear();
         }
+        Some(Streams {
+            video: video.clone(),
+            audio,
+            dubs: tracks,
+        })
     }
 
-    audio_data
-        .into_iter()
-        .max_by_key(|f| f.as_ref().unwrap().bandwidth)
-        .unwrap()
-        .take()
-}
+    /// the name of a video quality, like `1080P 高清`
+    pub fn name(&self, id: i32) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|(q, _)| *q == id)
+            .map(|(_, name)| name.as_str())
+    }
 
-pub fn choose_video_stream(video_data: &mut [MediaInfoOption]) -> MediaInfoOption {
-    video_data
-        .iter_mut()
-        .max_by_key(|f| f.as_ref().unwrap().bandwidth)
-        .unwrap()
-        .take()
-}
+    /// the estimated size in bytes of the given streams, from their bandwidth and the duration
+    pub fn estimated_size(&self, video: &MediaInfo, audio: &MediaInfo) -> Option<u64> {
+        Some(self.stream_size(video)? + self.stream_size(audio)?)
+    }
 
-pub fn extract_filename(url: &str, default: &str) -> String {
-    let u = Url::parse(url).unwrap();
-    let path = Path::new(u.path());
-    let filename = path.file_stem().unwrap().to_str().unwrap_or(default);
-    format!(
-        "{}.{}",
-        filename,
-        path.extension().unwrap().to_str().unwrap()
-    )
+    /// the estimated size in bytes of a single stream
+    pub fn stream_size(&self, stream: &MediaInfo) -> Option<u64> {
+        (self.duration > 0).then(|| stream.bandwidth() * self.duration / 8)
+    }
 }
 
 #[cfg(test)]
-mod parser_test {
+pub mod parser_test {
     use crate::http::client;
 
     use super::*;
@@ -153,12 +390,102 @@ mod parser_test {
         let url = "https://www.bilibili.com/video/BV1P1421t75S/?spm_id_from=333.337.search-card.all.click&vd_source=7b61f7ca2c7edcd57c0ffd1c17ee4e4c";
         let body = client.fetch_page_info(url).await.expect("1");
 
-        let mut play_info = extract_play_info(body).expect("failed to extract play info");
+        let play_info = extract_play_info(body).expect("failed to extract play info");
         // println!("{:#?}", play_info);
-        let audio_stream = choose_audio_stream(&mut play_info);
-
-        let video_stream = choose_video_stream(&mut play_info.dash.video);
+        let formats = Formats::new(play_info);
+        let (video_stream, audio_stream) = formats.best().unwrap();
         println!("{:#?}", video_stream);
         println!("{:#?}", audio_stream);
     }
+
+    /// a DASH stream as the play info lists it, audio when `height` is 0
+    pub fn stream(id: i32, bandwidth: i32, codecs: &str, height: i32) -> Value {
+        serde_json::json!({
+            "id": id,
+            "base_url": format!("https://example.com/{}", bandwidth),
+            "backup_url": [],
+            "bandwidth": bandwidth,
+            "codecs": codecs,
+            "mime_type": if height > 0 { "video/mp4" } else { "audio/mp4" },
+            "width": height * 16 / 9,
+            "height": height,
+            "segment_base": { "initialization": "0-1", "index_range": "2-3" },
+        })
+    }
+
+    fn audio(id: i32, bandwidth: i32) -> Value {
+        stream(id, bandwidth, "mp4a.40.2", 0)
+    }
+
+    #[test]
+    fn dubs() {
+        let play_info: PlayInfo = serde_json::from_value(serde_json::json!({
+            "accept_quality": [80],
+            "accept_description": ["1080P 高清"],
+            "accept_format": "mp4",
+            "dash": {
+                "duration": 60,
+                "video": [stream(80, 1000, "avc1.640032", 1080)],
+                "audio": [audio(30280, 320), audio(30216, 64)],
+                "dolby": { "type": 0, "audio": null },
+            },
+            "language": {
+                "items": [
+                    { "lang": "ja", "title": "日语" },
+                    { "lang": "yue", "title": "粤语", "audio": [audio(30216, 65), audio(30280, 321)] },
+                    { "lang": "zh", "title": "普通话", "audio": [audio(30280, 322)] },
+                ]
+            }
+        }))
+        .unwrap();
+        let formats = Formats::new(play_info);
+        assert_eq!(formats.language.as_ref().unwrap().iso639(), "jpn");
+        assert_eq!(formats.dubs.len(), 2);
+
+        let choice = FormatChoice::default();
+        let streams = formats.streams(&choice, &DubChoice::default()).unwrap();
+        assert_eq!(streams.audio.info.bandwidth(), 320);
+        assert!(streams.dubs.is_empty());
+
+        let dub = DubChoice {
+            lang: Some("粤语".into()),
+            all: false,
+        };
+        let streams = formats.streams(&choice, &dub).unwrap();
+        assert_eq!(streams.audio.language.unwrap().lang, "yue");
+        assert_eq!(streams.audio.info.bandwidth(), 321);
+
+        // the chosen audio quality is kept within the dub
+        let low = FormatChoice {
+            audio: Some(30216),
+            ..Default::default()
+        };
+        let streams = formats.streams(&low, &dub).unwrap();
+        assert_eq!(streams.audio.info.bandwidth(), 65);
+
+        // an unknown language falls back to the default audio
+        let dub = DubChoice {
+            lang: Some("en".into()),
+            all: true,
+        };
+        let streams = formats.streams(&choice, &dub).unwrap();
+        assert_eq!(streams.audio.info.bandwidth(), 320);
+        let langs: Vec<_> = streams
+            .dubs
+            .iter()
+            .map(|d| d.language.as_ref().unwrap().lang.as_str())
+            .collect();
+        assert_eq!(langs, ["yue", "zh"]);
+    }
+
+    #[test]
+    fn better_quality() {
+        let video: MediaInfo =
+            serde_json::from_value(stream(80, 3000000, "avc1.640032", 1080)).unwrap();
+        // the bandwidth of the manifest is not compared with the bit rate of the file
+        assert!(!video.is_better_than(1080, Some(80)));
+        assert!(video.is_better_than(1080, Some(64)));
+        assert!(!video.is_better_than(1080, None));
+        assert!(video.is_better_than(720, None));
+    }
 }
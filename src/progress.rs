@@ -0,0 +1,392 @@
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::http::download::Task;
+
+/// 进度的输出方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// 进度条
+    Bar,
+    /// 每行一个 JSON 事件
+    Json,
+}
+
+static MODE: OnceLock<ProgressMode> = OnceLock::new();
+
+/// 设置进度的输出方式，只在启动时调用一次
+pub fn set_mode(mode: ProgressMode) {
+    let _ = MODE.set(mode);
+}
+
+pub fn mode() -> ProgressMode {
+    *MODE.get().unwrap_or(&ProgressMode::Bar)
+}
+
+/// 是否正在显示全屏界面
+static FULLSCREEN: AtomicBool = AtomicBool::new(false);
+/// 显示全屏界面时保存的提示信息
+static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+const LOG_LINES: usize = 100;
+
+/// 显示选择界面或下载面板时不再输出进度条，提示信息保存到日志中，由下载面板显示。
+/// 退出全屏界面时清空日志
+pub fn set_fullscreen(on: bool) {
+    FULLSCREEN.store(on, Ordering::Relaxed);
+    if !on {
+        LOG.lock().unwrap().clear();
+    }
+}
+
+fn fullscreen() -> bool {
+    FULLSCREEN.load(Ordering::Relaxed)
+}
+
+/// the last `n` messages logged while a fullscreen ui is shown
+pub fn last_logs(n: usize) -> Vec<String> {
+    let log = LOG.lock().unwrap();
+    log[log.len().saturating_sub(n)..].to_vec()
+}
+
+fn capture(msg: &str) -> bool {
+    if !fullscreen() {
+        return false;
+    }
+    let mut log = LOG.lock().unwrap();
+    if log.len() >= LOG_LINES {
+        log.remove(0);
+    }
+    log.push(msg.to_string());
+    true
+}
+
+pub fn print_info(msg: String) {
+    if capture(&msg) {
+        return;
+    }
+    if mode() == ProgressMode::Json {
+        eprintln!("{}", msg);
+    } else {
+        println!("{}", msg);
+    }
+}
+
+pub fn print_error(msg: String) {
+    if !capture(&msg) {
+        eprintln!("{}", msg);
+    }
+}
+
+/// 打印提示信息。JSON 模式下 stdout 只输出 JSON，提示信息改为输出到 stderr
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::progress::print_info(format!($($arg)*))
+    };
+}
+/// 打印错误信息到 stderr
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::progress::print_error(format!($($arg)*))
+    };
+}
+pub(crate) use {error, info};
+
+/// `--progress json` 输出的事件
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    TaskStarted {
+        key: &'a str,
+        title: &'a str,
+    },
+    Progress {
+        key: &'a str,
+        media: &'a str,
+        bytes: u64,
+        total: u64,
+        /// bytes/s
+        speed: u64,
+    },
+    MergeStarted {
+        key: &'a str,
+    },
+    Finished {
+        key: &'a str,
+        path: &'a Path,
+    },
+    Skipped {
+        key: &'a str,
+        path: &'a Path,
+        reason: &'a str,
+    },
+    Error {
+        key: &'a str,
+        message: &'a str,
+    },
+    /// 中断或暂停，之后可以继续下载
+    Paused {
+        key: &'a str,
+    },
+    Cancelled {
+        key: &'a str,
+    },
+}
+
+fn emit(event: Event) {
+    println!("{}", serde_json::to_string(&event).unwrap());
+}
+
+pub fn task_started(task: &Task) {
+    match mode() {
+        ProgressMode::Bar => info!("[Download] 下载视频: {}", task.title),
+        ProgressMode::Json => emit(Event::TaskStarted {
+            key: &task.key,
+            title: &task.title,
+        }),
+    }
+}
+
+pub fn merge_started(task: &Task) {
+    if mode() == ProgressMode::Json {
+        emit(Event::MergeStarted { key: &task.key });
+    }
+}
+
+pub fn task_finished(task: &Task, path: &Path) {
+    match mode() {
+        ProgressMode::Bar => info!("下载完成: {}\n", path.display()),
+        ProgressMode::Json => emit(Event::Finished {
+            key: &task.key,
+            path,
+        }),
+    }
+}
+
+pub fn task_skipped(task: &Task, path: &Path, reason: &str) {
+    match mode() {
+        ProgressMode::Bar => info!("{}，跳过: {}\n", reason, path.display()),
+        ProgressMode::Json => emit(Event::Skipped {
+            key: &task.key,
+            path,
+            reason,
+        }),
+    }
+}
+
+pub fn task_failed(task: &Task, message: &str) {
+    match mode() {
+        ProgressMode::Bar => error!("下载失败: {}", message),
+        ProgressMode::Json => emit(Event::Error {
+            key: &task.key,
+            message,
+        }),
+    }
+}
+
+pub fn task_paused(task: &Task) {
+    match mode() {
+        ProgressMode::Bar => info!("已暂停: {}", task.title),
+        ProgressMode::Json => emit(Event::Paused { key: &task.key }),
+    }
+}
+
+pub fn task_cancelled(task: &Task) {
+    match mode() {
+        ProgressMode::Bar => info!("已取消: {}", task.title),
+        ProgressMode::Json => emit(Event::Cancelled { key: &task.key }),
+    }
+}
+
+/// JSON 模式下两次 progress 事件的最小间隔
+const EMIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 一个任务中所有音视频流的下载进度
+#[derive(Debug)]
+pub struct TaskProgress {
+    multi: MultiProgress,
+    key: String,
+    bytes: AtomicU64,
+    total: AtomicU64,
+    /// the part of `bytes` kept from an earlier run, which does not count for the speed
+    resumed: AtomicU64,
+}
+
+impl TaskProgress {
+    pub fn new(key: &str) -> Self {
+        Self {
+            multi: MultiProgress::new(),
+            key: key.to_string(),
+            bytes: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            resumed: AtomicU64::new(0),
+        }
+    }
+
+    /// the downloaded and total bytes of the streams started so far
+    pub fn bytes(&self) -> (u64, u64) {
+        (
+            self.bytes.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// the bytes downloaded in this run, without those resumed from a checkpoint
+    pub fn transferred(&self) -> u64 {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        bytes.saturating_sub(self.resumed.load(Ordering::Relaxed))
+    }
+
+    /// 重新开始下载任务时清零进度
+    pub fn reset(&self) {
+        self.bytes.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.resumed.store(0, Ordering::Relaxed);
+    }
+}
+
+/// 单个音频或视频流的下载进度
+pub struct MediaProgress<'a> {
+    task: &'a TaskProgress,
+    bar: Option<ProgressBar>,
+    media: String,
+    total: u64,
+    bytes: AtomicU64,
+    /// the bytes kept from an earlier run
+    resumed: u64,
+    start: Instant,
+    last_emit: Mutex<Instant>,
+}
+
+impl<'a> MediaProgress<'a> {
+    pub fn new(task: &'a TaskProgress, media: &str, total: u64) -> Self {
+        task.total.fetch_add(total, Ordering::Relaxed);
+        let bar = (mode() == ProgressMode::Bar && !fullscreen()).then(|| {
+            let pb = task.multi.add(ProgressBar::new(total));
+            pb.set_message(format!("downloading {media}"));
+            pb.set_style(
+                ProgressStyle::with_template(r#"{spinner:.green} [{msg}] [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})"#)
+                .unwrap()
+                .progress_chars("#>-")
+            );
+            pb
+        });
+        let now = Instant::now();
+        Self {
+            task,
+            bar,
+            media: media.to_string(),
+            total,
+            bytes: AtomicU64::new(0),
+            resumed: 0,
+            start: now,
+            last_emit: Mutex::new(now),
+        }
+    }
+
+    /// start from the bytes saved by an earlier run, they are shown but left out of the speed
+    pub fn resumed(mut self, n: u64) -> Self {
+        self.resumed = n;
+        self.bytes.store(n, Ordering::Relaxed);
+        self.task.bytes.fetch_add(n, Ordering::Relaxed);
+        self.task.resumed.fetch_add(n, Ordering::Relaxed);
+        if let Some(pb) = &self.bar {
+            // let the estimator see the jump, then forget it
+            pb.set_position(n);
+            pb.tick();
+            pb.reset_eta();
+        }
+        self
+    }
+
+    pub fn inc(&self, n: u64) {
+        self.bytes.fetch_add(n, Ordering::Relaxed);
+        self.task.bytes.fetch_add(n, Ordering::Relaxed);
+        if let Some(pb) = &self.bar {
+            pb.inc(n);
+            return;
+        }
+        if mode() != ProgressMode::Json {
+            return;
+        }
+        let mut last = self.last_emit.lock().unwrap();
+        if last.elapsed() >= EMIT_INTERVAL {
+            *last = Instant::now();
+            self.emit_progress();
+        }
+    }
+
+    /// 重新开始下载时清零进度
+    pub fn reset(&self) {
+        let bytes = self.bytes.swap(0, Ordering::Relaxed);
+        self.task.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(pb) = &self.bar {
+            pb.reset();
+        }
+    }
+
+    pub fn finish(&self) {
+        match &self.bar {
+            Some(pb) => pb.finish_with_message("✓"),
+            None if mode() == ProgressMode::Json => self.emit_progress(),
+            None => {}
+        }
+    }
+
+    fn emit_progress(&self) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            (bytes.saturating_sub(self.resumed) as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        emit(Event::Progress {
+            key: &self.task.key,
+            media: &self.media,
+            bytes,
+            total: self.total,
+            speed,
+        });
+    }
+}
+
+#[cfg(test)]
+mod progress_test {
+    use super::*;
+
+    #[test]
+    fn event_json() {
+        let event = Event::Progress {
+            key: "ep830937",
+            media: "video",
+            bytes: 1024,
+            total: 4096,
+            speed: 512,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"progress","key":"ep830937","media":"video","bytes":1024,"total":4096,"speed":512}"#
+        );
+    }
+
+    #[test]
+    fn resumed_bytes() {
+        let task = TaskProgress::new("ep830937");
+        let pb = MediaProgress::new(&task, "video", 100).resumed(40);
+        pb.inc(10);
+        assert_eq!(task.bytes(), (50, 100));
+        // only the bytes of this run count for the speed
+        assert_eq!(task.transferred(), 10);
+        task.reset();
+        assert_eq!(task.transferred(), 0);
+    }
+}
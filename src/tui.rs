@@ -1,34 +1,137 @@
+use chrono::{DateTime, Local};
 use ratatui::{
     buffer::Buffer,
     crossterm::{
-        event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+        event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
         execute,
         terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     },
-    layout::{Constraint, Layout, Rect},
+    layout::{Constraint, Flex, Layout, Rect},
     prelude::CrosstermBackend,
     style::{Color, Style},
+    text::{Line, Text},
     widgets::{
-        Block, Borders, HighlightSpacing, List, ListItem, ListState, Paragraph, StatefulWidget,
-        Widget,
+        Block, Borders, Clear, HighlightSpacing, List, ListItem, ListState, Paragraph,
+        StatefulWidget, Widget, Wrap,
     },
     Terminal,
 };
-use std::io::{self, stdout, Stdout};
+use std::{
+    io::{self, stdout, Stdout},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc as tokio_mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
 
-use crate::http::download::Task;
+use crate::{
+    cli::Items,
+    http::{
+        client::Client,
+        download::{Access, DownloadTask, Task, TaskState},
+    },
+    parser::{FormatChoice, Formats},
+    progress::{self, info},
+};
 
 type Tui = Terminal<CrosstermBackend<Stdout>>;
 
 pub struct SelectionUI<'u> {
     should_exit: bool,
     video_list: VideoList<'u>,
+    mode: Mode,
+    /// the number of rows of the list in the last frame, used by PageUp/PageDown
+    page: usize,
+    /// 每个视频的播放信息，光标停留或打开格式选择时获取
+    formats: Vec<FormatState>,
+    fetcher: Option<Fetcher>,
+    picker: Option<Picker>,
+}
+
+/// 单个视频的格式选择窗口
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Picker {
+    /// the index of the video in `videos`
+    index: usize,
+    /// the cursors in the video and audio lists
+    video: usize,
+    audio: usize,
+    /// whether the audio list has the focus
+    on_audio: bool,
+}
+
+/// 选择界面中一个视频的播放信息
+enum FormatState {
+    Pending,
+    Loaded(Formats),
+    Failed(String),
+    /// 音频区的歌曲只有一个音频流，不需要获取和选择格式
+    Song,
+}
+
+type FetchResult = (usize, Result<Formats, String>);
+
+/// how long the cursor has to rest on a video before its play info is fetched
+const SETTLE: Duration = Duration::from_millis(300);
+
+/// fetches the play info of the videos the user looks at, one at a time
+struct Fetcher {
+    wanted: tokio_mpsc::UnboundedSender<usize>,
+    results: mpsc::Receiver<FetchResult>,
+    /// the highlighted video and since when, so that scrolling through the list fetches nothing
+    highlighted: Option<(usize, Instant)>,
+    /// the videos already sent to the fetch task
+    requested: Vec<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for Fetcher {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// fetch the wanted videos in order.
+/// `links` is `None` for the videos which have no formats to fetch
+async fn fetch_wanted(
+    client: Arc<Client>,
+    links: Vec<Option<String>>,
+    mut wanted: tokio_mpsc::UnboundedReceiver<usize>,
+    results: mpsc::Sender<FetchResult>,
+) {
+    while let Some(i) = wanted.recv().await {
+        let Some(Some(link)) = links.get(i) else {
+            continue;
+        };
+        let res = client.fetch_formats(link).await.map_err(|e| e.to_string());
+        if results.send((i, res)).is_err() {
+            // the selection ui is closed
+            return;
+        }
+    }
+}
+
+/// 选择界面的输入模式
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Normal,
+    /// 正在输入搜索内容
+    Search,
+    /// 范围选择，`anchor` 为开始位置在 `visible` 中的下标
+    Visual {
+        anchor: usize,
+    },
 }
 
 struct VideoList<'l> {
     videos: &'l [Task],
+    /// indexes into `videos` of the items matching `filter`, the list state indexes into this
+    visible: Vec<usize>,
+    filter: String,
     state: ListState,
     is_selected: Vec<bool>,
+    /// member-only videos can not be selected without a VIP membership
+    locked: Vec<bool>,
     select_all: bool,
 }
 
@@ -36,44 +139,253 @@ impl<'u> SelectionUI<'u> {
     pub fn new(video_list: &'u [Task]) -> Self {
         let vl = VideoList {
             videos: video_list,
-            state: ListState::default(),
-            is_selected: vec![true; video_list.len()],
+            visible: (0..video_list.len()).collect(),
+            filter: String::new(),
+            state: ListState::default().with_selected(Some(0)),
+            is_selected: video_list.iter().map(|v| !v.archived).collect(),
+            locked: vec![false; video_list.len()],
             select_all: true,
         };
         Self {
             should_exit: false,
             video_list: vl,
+            mode: Mode::Normal,
+            page: 10,
+            formats: video_list
+                .iter()
+                .map(|v| match v.song {
+                    Some(_) => FormatState::Song,
+                    None => FormatState::Pending,
+                })
+                .collect(),
+            fetcher: None,
+            picker: None,
         }
     }
+
+    /// lock the member-only videos unless the user is a VIP
+    pub fn vip(mut self, vip: bool) -> Self {
+        let vl = &mut self.video_list;
+        vl.locked = vl
+            .videos
+            .iter()
+            .map(|v| !vip && v.details.access == Access::Member)
+            .collect();
+        for i in 0..vl.len() {
+            vl.set(i, vl.is_selected[i]);
+        }
+        self
+    }
+
+    /// fetch the play info of the highlighted videos to show their formats and sizes
+    pub fn fetch_formats(mut self, client: Arc<Client>) -> Self {
+        let (wanted_tx, wanted_rx) = tokio_mpsc::unbounded_channel();
+        let (results_tx, results_rx) = mpsc::channel();
+        let links: Vec<_> = self
+            .video_list
+            .videos
+            .iter()
+            .map(|v| v.song.is_none().then(|| v.link.clone()))
+            .collect();
+        self.fetcher = Some(Fetcher {
+            wanted: wanted_tx,
+            results: results_rx,
+            highlighted: None,
+            requested: vec![false; links.len()],
+            handle: tokio::spawn(fetch_wanted(client, links, wanted_rx, results_tx)),
+        });
+        self
+    }
 }
 
 impl SelectionUI<'_> {
     pub fn run(&mut self) -> io::Result<()> {
         let mut terminal = init_terminal()?;
         while !self.should_exit {
+            self.update_formats();
             terminal.draw(|f| f.render_widget(&mut *self, f.area()))?;
-            if let Event::Key(key) = event::read()? {
-                self.handle_key(key);
+            if event::poll(TICK)? {
+                if let Event::Key(key) = event::read()? {
+                    self.handle_key(key);
+                }
             }
         }
         restore()?;
+        self.fetcher = None;
         Ok(())
     }
 
+    /// receive the fetched play info, and ask for the highlighted video once the cursor rests on it
+    fn update_formats(&mut self) {
+        let current = self.current_video();
+        let Some(fetcher) = &mut self.fetcher else {
+            return;
+        };
+        while let Ok((i, res)) = fetcher.results.try_recv() {
+            self.formats[i] = match res {
+                Ok(mut formats) => {
+                    if formats.duration == 0 {
+                        let details = &self.video_list.videos[i].details;
+                        formats.duration = details.duration.unwrap_or_default();
+                    }
+                    FormatState::Loaded(formats)
+                }
+                Err(e) => FormatState::Failed(e),
+            };
+        }
+        match (current, fetcher.highlighted) {
+            (Some(i), Some((h, since))) if i == h => {
+                if since.elapsed() >= SETTLE {
+                    self.request_formats(i);
+                }
+            }
+            _ => fetcher.highlighted = current.map(|i| (i, Instant::now())),
+        }
+    }
+
+    /// ask for the play info of a video once, unless there is nothing to fetch
+    fn request_formats(&mut self, i: usize) {
+        let Some(fetcher) = &mut self.fetcher else {
+            return;
+        };
+        if matches!(self.formats[i], FormatState::Pending) && !fetcher.requested[i] {
+            fetcher.requested[i] = true;
+            let _ = fetcher.wanted.send(i);
+        }
+    }
+
+    /// the index of the highlighted video
+    fn current_video(&self) -> Option<usize> {
+        self.video_list
+            .selected_pos()
+            .map(|p| self.video_list.visible[p])
+    }
+
+    /// the estimated size of a video with the chosen formats
+    fn estimated_size(&self, i: usize) -> Option<u64> {
+        match &self.formats[i] {
+            FormatState::Loaded(f) => f
+                .pick(&self.video_list.videos[i].format())
+                .and_then(|(v, a)| f.estimated_size(v, a)),
+            _ => None,
+        }
+    }
+
+    /// open the format picker of the highlighted video, with the cursors on the current choice
+    fn open_picker(&mut self) {
+        let Some(index) = self.current_video() else {
+            return;
+        };
+        if let FormatState::Song = self.formats[index] {
+            return;
+        }
+        self.request_formats(index);
+        let mut picker = Picker {
+            index,
+            video: 0,
+            audio: 0,
+            on_audio: false,
+        };
+        if let FormatState::Loaded(f) = &self.formats[index] {
+            if let Some((v, a)) = f.pick(&self.video_list.videos[index].format()) {
+                picker.video = f.video.iter().position(|s| std::ptr::eq(s, v)).unwrap_or(0);
+                picker.audio = f.audio.iter().position(|s| std::ptr::eq(s, a)).unwrap_or(0);
+            }
+        }
+        self.picker = Some(picker);
+    }
+
+    fn handle_picker_key(&mut self, key: KeyEvent) {
+        let Some(picker) = &mut self.picker else {
+            return;
+        };
+        let FormatState::Loaded(f) = &self.formats[picker.index] else {
+            if matches!(key.code, KeyCode::Esc | KeyCode::Char('q')) {
+                self.picker = None;
+            }
+            return;
+        };
+        let (cursor, len) = if picker.on_audio {
+            (&mut picker.audio, f.audio.len())
+        } else {
+            (&mut picker.video, f.video.len())
+        };
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => *cursor = cursor.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                *cursor = (*cursor + 1).min(len.saturating_sub(1))
+            }
+            KeyCode::Tab
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::Char('h')
+            | KeyCode::Char('l') => picker.on_audio = !picker.on_audio,
+            KeyCode::Enter => {
+                // the best streams are kept as the default so that they follow the play info
+                let video = &f.video[picker.video];
+                let choice = FormatChoice {
+                    video: (picker.video > 0).then(|| (video.id(), video.codecs().to_owned())),
+                    audio: (picker.audio > 0).then(|| f.audio[picker.audio].id()),
+                };
+                self.video_list.videos[picker.index].set_format(choice);
+                self.picker = None;
+            }
+            KeyCode::Esc | KeyCode::Char('q') => self.picker = None,
+            _ => {}
+        }
+    }
+
     fn handle_key(&mut self, key: KeyEvent) {
         if key.kind != KeyEventKind::Press {
             return;
         }
+        if self.picker.is_some() {
+            self.handle_picker_key(key);
+            return;
+        }
+        if self.mode == Mode::Search {
+            self.handle_search_key(key);
+            return;
+        }
+        if key.modifiers.contains(KeyModifiers::SHIFT)
+            && matches!(key.code, KeyCode::Up | KeyCode::Down)
+        {
+            // shift-select extends a range like the visual mode
+            if let (Mode::Normal, Some(pos)) = (self.mode, self.video_list.state.selected()) {
+                self.mode = Mode::Visual { anchor: pos };
+            }
+        }
         match key.code {
-            KeyCode::Up => {
+            KeyCode::Up | KeyCode::Char('k') => {
                 self.select_prev();
             }
-            KeyCode::Down => {
+            KeyCode::Down | KeyCode::Char('j') => {
                 self.select_next();
             }
-            KeyCode::Char('d') => {
+            KeyCode::PageUp => self.move_cursor(-(self.page as isize)),
+            KeyCode::PageDown => self.move_cursor(self.page as isize),
+            KeyCode::Home | KeyCode::Char('g') => self.move_cursor(isize::MIN),
+            KeyCode::End | KeyCode::Char('G') => self.move_cursor(isize::MAX),
+            KeyCode::Char('d') | KeyCode::Char(' ') => {
                 self.toggle_status();
             }
+            KeyCode::Char('v') => match self.mode {
+                Mode::Visual { .. } => self.toggle_status(),
+                _ => {
+                    if let Some(pos) = self.video_list.state.selected() {
+                        self.mode = Mode::Visual { anchor: pos };
+                    }
+                }
+            },
+            KeyCode::Char('/') => {
+                self.mode = Mode::Search;
+            }
+            KeyCode::Esc => {
+                if self.mode == Mode::Normal {
+                    self.video_list.set_filter(String::new());
+                }
+                self.mode = Mode::Normal;
+            }
             KeyCode::Enter => self.should_exit = true,
 
             KeyCode::Char('n') => {
@@ -84,30 +396,93 @@ impl SelectionUI<'_> {
             KeyCode::Char('a') => {
                 self.toggle_all_status(None);
             }
+            KeyCode::Char('i') => {
+                self.invert_status();
+            }
+            KeyCode::Char('f') => self.open_picker(),
             _ => {}
         }
     }
 
+    /// incremental search, the list is filtered while typing
+    fn handle_search_key(&mut self, key: KeyEvent) {
+        let mut filter = self.video_list.filter.clone();
+        match key.code {
+            KeyCode::Enter => {
+                self.mode = Mode::Normal;
+                return;
+            }
+            KeyCode::Esc => {
+                filter.clear();
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Backspace => {
+                filter.pop();
+            }
+            KeyCode::Char(c) => filter.push(c),
+            _ => return,
+        }
+        self.video_list.set_filter(filter);
+    }
+
     fn select_next(&mut self) {
-        self.video_list.state.select_next();
+        self.move_cursor(1);
     }
 
     fn select_prev(&mut self) {
-        self.video_list.state.select_previous();
+        self.move_cursor(-1);
     }
 
+    /// move the cursor by `offset` items, stopping at both ends of the visible list
+    fn move_cursor(&mut self, offset: isize) {
+        let last = self.video_list.visible.len().saturating_sub(1);
+        let pos = self.video_list.selected_pos().unwrap_or(0);
+        let pos = pos.saturating_add_signed(offset).min(last);
+        self.video_list.state.select(Some(pos));
+    }
+
+    /// toggle the current item, or the range in visual mode which is set to the opposite of
+    /// the item where the range started
     fn toggle_status(&mut self) {
-        if let Some(i) = self.video_list.selected() {
-            self.video_list.is_selected[i] = !self.video_list.is_selected[i];
+        let Some(pos) = self.video_list.selected_pos() else {
+            return;
+        };
+        let (start, end) = match self.mode {
+            Mode::Visual { anchor } => (anchor.min(pos), anchor.max(pos)),
+            _ => (pos, pos),
+        };
+        let anchor = match self.mode {
+            Mode::Visual { anchor } => anchor,
+            _ => pos,
+        };
+        let vl = &mut self.video_list;
+        let status = !vl.is_selected[vl.visible[anchor]];
+        for pos in start..=end {
+            vl.set(vl.visible[pos], status);
         }
+        self.mode = Mode::Normal;
     }
 
+    /// select or deselect every visible item, `default` sets every item regardless of the filter
     fn toggle_all_status(&mut self, default: Option<bool>) {
+        let vl = &mut self.video_list;
         if let Some(s) = default {
-            self.video_list.is_selected = vec![s; self.video_list.len()];
-        } else {
-            self.video_list.select_all = !self.video_list.select_all;
-            self.video_list.is_selected = vec![self.video_list.select_all; self.video_list.len()];
+            for i in 0..vl.len() {
+                vl.set(i, s);
+            }
+            return;
+        }
+        vl.select_all = !vl.select_all;
+        for pos in 0..vl.visible.len() {
+            vl.set(vl.visible[pos], vl.select_all);
+        }
+    }
+
+    fn invert_status(&mut self) {
+        let vl = &mut self.video_list;
+        for pos in 0..vl.visible.len() {
+            let i = vl.visible[pos];
+            vl.set(i, !vl.is_selected[i]);
         }
     }
 
@@ -121,51 +496,311 @@ impl VideoList<'_> {
         self.videos.len()
     }
 
-    fn selected(&self) -> Option<usize> {
-        self.state.selected()
+    /// the cursor position in `visible`
+    fn selected_pos(&self) -> Option<usize> {
+        self.state.selected().filter(|&p| p < self.visible.len())
+    }
+
+    /// filter the list by title, keeping the cursor on the same video when it still matches
+    fn set_filter(&mut self, filter: String) {
+        let current = self.selected_pos().map(|p| self.visible[p]);
+        let needle = filter.to_lowercase();
+        self.visible = self
+            .videos
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.title.to_lowercase().contains(&needle))
+            .map(|(i, _)| i)
+            .collect();
+        self.filter = filter;
+        let pos = current
+            .and_then(|c| self.visible.iter().position(|&i| i == c))
+            .unwrap_or(0);
+        self.state.select(Some(pos));
+    }
+
+    /// select or deselect a video, locked videos are always deselected
+    fn set(&mut self, i: usize, selected: bool) {
+        self.is_selected[i] = selected && !self.locked[i];
+    }
+
+    fn selected_count(&self) -> usize {
+        self.is_selected.iter().filter(|&&s| s).count()
     }
 }
 
 impl Widget for &mut SelectionUI<'_> {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let [main_area, foot_area] =
-            Layout::vertical([Constraint::Fill(1), Constraint::Length(2)]).areas(area);
+        let [main_area, status_area, foot_area] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(1),
+            Constraint::Length(2),
+        ])
+        .areas(area);
 
+        let [list_area, detail_area] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main_area);
+
+        self.render_status(status_area, buf);
         SelectionUI::render_footer(foot_area, buf);
-        self.render_list(main_area, buf);
+        self.render_list(list_area, buf);
+        self.render_details(detail_area, buf);
+        if let Some(picker) = self.picker {
+            self.render_picker(picker, area, buf);
+        }
     }
 }
 
 impl SelectionUI<'_> {
     fn render_footer(area: Rect, buf: &mut Buffer) {
         Paragraph::new(
-            "<↑/↓>: 上下移动; <d>: 选择 / 取消选择; <a>: 全选 / 全不选; <Enter>: 确认; <n>: 取消下载",
+            "<↑/↓/j/k/PgUp/PgDn/Home/End>: 移动; <d/Space>: 选择 / 取消选择; <v/Shift+↑/↓>: 范围选择; <a>: 全选 / 全不选; <i>: 反选; </>: 搜索; <f>: 选择格式; <Enter>: 确认; <n>: 取消下载",
         )
         .centered()
+        .wrap(Wrap { trim: true })
         .render(area, buf);
     }
 
+    fn render_status(&self, area: Rect, buf: &mut Buffer) {
+        let vl = &self.video_list;
+        let mut status = format!("已选择 {} / {}", vl.selected_count(), vl.len());
+        let (mut size, mut unknown) = (0, 0);
+        for (i, _) in vl.is_selected.iter().enumerate().filter(|(_, &s)| s) {
+            match self.estimated_size(i) {
+                Some(s) => size += s,
+                None => unknown += 1,
+            }
+        }
+        status.push_str(&format!("  预计大小: {}", format_bytes(size as f64)));
+        if unknown > 0 {
+            status.push_str(&format!(" ({} 项未知)", unknown));
+        }
+        match self.mode {
+            Mode::Search => status.push_str(&format!("  搜索: {}▏", vl.filter)),
+            _ if !vl.filter.is_empty() => status.push_str(&format!(
+                "  搜索: {} (匹配 {} 项，<Esc> 清除)",
+                vl.filter,
+                vl.visible.len()
+            )),
+            _ => {}
+        }
+        if let Mode::Visual { .. } = self.mode {
+            status.push_str("  -- 范围选择 --");
+        }
+        Paragraph::new(status).render(area, buf);
+    }
+
+    fn render_details(&self, area: Rect, buf: &mut Buffer) {
+        let block = Block::new().title("Details").borders(Borders::ALL);
+        let Some(i) = self.current_video() else {
+            block.render(area, buf);
+            return;
+        };
+        let video = &self.video_list.videos[i];
+        let details = &video.details;
+        let mut lines = vec![Line::from(video.title.clone()), Line::default()];
+        if let Some(group) = &details.group {
+            lines.push(Line::from(format!("分组: {}", group)));
+        }
+        if let Some(d) = details.duration {
+            lines.push(Line::from(format!("时长: {}", format_duration(d as f64))));
+        }
+        if let Some(time) = details
+            .pub_time
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+        {
+            let time = time.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+            lines.push(Line::from(format!("发布时间: {}", time)));
+        }
+        if details.access != Access::Free {
+            lines.push(Line::from(format!("权限: {}", details.access.label())));
+        }
+        if let Some(badge) = &details.badge {
+            lines.push(Line::from(format!("标记: {}", badge)).style(Color::Rgb(251, 114, 153)));
+        }
+        if video.archived {
+            lines.push(Line::from("已下载"));
+        }
+        lines.push(Line::default());
+        match &self.formats[i] {
+            FormatState::Pending => lines.push(Line::from("正在获取播放信息……")),
+            FormatState::Song => {
+                lines.push(Line::from("音频:"));
+                lines.push(Line::from("* 单一音频流，下载时使用可获取的最高音质"));
+            }
+            FormatState::Failed(e) => {
+                lines.push(Line::from(format!("获取播放信息失败: {}", e)).style(Color::Red))
+            }
+            FormatState::Loaded(f) => {
+                // the chosen streams are marked
+                let chosen = f.pick(&video.format());
+                let mark = |s| {
+                    if chosen.is_some_and(|(v, a)| std::ptr::eq(v, s) || std::ptr::eq(a, s)) {
+                        "*"
+                    } else {
+                        " "
+                    }
+                };
+                lines.push(Line::from("画质:"));
+                for v in f.video.iter() {
+                    let name = f.name(v.id()).unwrap_or_default();
+                    lines.push(Line::from(format!(
+                        "{} {} {} {}/s",
+                        mark(v),
+                        name,
+                        v.quality(),
+                        format_bytes(v.bandwidth() as f64 / 8.0)
+                    )));
+                }
+                lines.push(Line::from("音频:"));
+                for a in f.audio.iter() {
+                    lines.push(Line::from(format!("{} {}", mark(a), a.quality())));
+                }
+                if let Some(size) = self.estimated_size(i) {
+                    lines.push(Line::default());
+                    lines.push(Line::from(format!(
+                        "预计大小: {}",
+                        format_bytes(size as f64)
+                    )));
+                }
+            }
+        }
+        Paragraph::new(lines)
+            .block(block)
+            .wrap(Wrap { trim: false })
+            .render(area, buf);
+    }
+
+    fn render_picker(&self, picker: Picker, area: Rect, buf: &mut Buffer) {
+        let [area] = Layout::horizontal([Constraint::Percentage(80)])
+            .flex(Flex::Center)
+            .areas(area);
+        let [area] = Layout::vertical([Constraint::Percentage(70)])
+            .flex(Flex::Center)
+            .areas(area);
+        Clear.render(area, buf);
+        let title = format!("选择格式: {}", self.video_list.videos[picker.index].title);
+        let block = Block::new().title(title).borders(Borders::ALL);
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        let [lists_area, help_area] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(inner);
+        Paragraph::new("<↑/↓>: 移动; <Tab/←/→>: 切换视频 / 音频; <Enter>: 确认; <Esc>: 取消")
+            .centered()
+            .render(help_area, buf);
+
+        let f = match &self.formats[picker.index] {
+            FormatState::Loaded(f) => f,
+            FormatState::Pending => {
+                Paragraph::new("正在获取播放信息……").render(lists_area, buf);
+                return;
+            }
+            FormatState::Song => return,
+            FormatState::Failed(e) => {
+                Paragraph::new(format!("获取播放信息失败: {}", e))
+                    .style(Color::Red)
+                    .wrap(Wrap { trim: false })
+                    .render(lists_area, buf);
+                return;
+            }
+        };
+        let size = |s| {
+            f.stream_size(s)
+                .map(|b| format!(" ~{}", format_bytes(b as f64)))
+                .unwrap_or_default()
+        };
+        let video: Vec<ListItem> = f
+            .video
+            .iter()
+            .map(|v| {
+                let name = f.name(v.id()).unwrap_or_default();
+                ListItem::new(format!("{} {}{}", name, v.quality(), size(v)))
+            })
+            .collect();
+        let audio: Vec<ListItem> = f
+            .audio
+            .iter()
+            .map(|a| ListItem::new(format!("{}{}", a.quality(), size(a))))
+            .collect();
+
+        let [video_area, audio_area] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(lists_area);
+        let focused = Style::default().fg(Color::Rgb(255, 167, 38));
+        for (items, cursor, is_focused, title, area) in [
+            (video, picker.video, !picker.on_audio, "画质", video_area),
+            (audio, picker.audio, picker.on_audio, "音频", audio_area),
+        ] {
+            let list = List::new(items)
+                .block(Block::new().title(title).borders(Borders::ALL))
+                .highlight_symbol(">> ")
+                .highlight_spacing(HighlightSpacing::Always)
+                .highlight_style(if is_focused {
+                    focused
+                } else {
+                    Style::default()
+                });
+            let mut state = ListState::default().with_selected(Some(cursor));
+            StatefulWidget::render(list, area, buf, &mut state);
+        }
+    }
+
     fn render_list(&mut self, area: Rect, buf: &mut Buffer) {
         let block = Block::new().title("Select Video").borders(Borders::ALL);
+        self.page = area.height.saturating_sub(2).max(1) as usize;
 
+        let cursor = self.video_list.selected_pos();
+        let range = match (self.mode, cursor) {
+            (Mode::Visual { anchor }, Some(pos)) => Some((anchor.min(pos), anchor.max(pos))),
+            _ => None,
+        };
         let items: Vec<ListItem> = self
             .video_list
-            .videos
+            .visible
             .iter()
             .enumerate()
-            .map(|(i, v)| {
+            .map(|(pos, &i)| {
+                let v = &self.video_list.videos[i];
                 let is_checked = if self.video_list.is_selected[i] {
                     "[✓]"
                 } else {
                     "[ ]"
                 };
-                let content = format!("{} {}", is_checked, v.title);
-                let style = if self.video_list.is_selected[i] {
+                let archived = if v.archived { " [已下载]" } else { "" };
+                let locked = if self.video_list.locked[i] {
+                    " [需要大会员]"
+                } else {
+                    ""
+                };
+                let custom = if v.format() != FormatChoice::default() {
+                    " [自选格式]"
+                } else {
+                    ""
+                };
+                let content = format!("{} {}{}{}{}", is_checked, v.title, archived, locked, custom);
+                // a header before the first item of every group
+                let prev = pos.checked_sub(1).map(|p| self.video_list.visible[p]);
+                let prev_group = prev.map(|p| &self.video_list.videos[p].details.group);
+                let group = &v.details.group;
+                let mut text = Text::default();
+                if prev_group.map_or(group.is_some(), |g| g != group) {
+                    let name = group.as_deref().unwrap_or("正片");
+                    text.push_line(Line::from(format!("── {} ──", name)).style(Color::Gray));
+                }
+                text.push_line(Line::from(content));
+                let mut style = if self.video_list.locked[i] {
+                    Style::default().fg(Color::DarkGray)
+                } else if self.video_list.is_selected[i] {
                     Style::default().fg(Color::Rgb(255, 167, 38))
                 } else {
                     Style::default().fg(Color::Rgb(120, 144, 156))
                 };
-                ListItem::new(content).style(style)
+                if range.is_some_and(|(start, end)| pos >= start && pos <= end) {
+                    style = style.bg(Color::Rgb(55, 71, 79));
+                }
+                ListItem::new(text).style(style)
             })
             .collect();
 
@@ -178,6 +813,231 @@ impl SelectionUI<'_> {
     }
 }
 
+/// 下载面板的刷新间隔
+const TICK: Duration = Duration::from_millis(250);
+const LOG_HEIGHT: u16 = 6;
+
+/// 下载时的全屏面板，显示每个任务的状态、速度和剩余时间，并可以管理下载队列
+pub struct Dashboard<'d> {
+    dl: &'d DownloadTask,
+    /// stops the whole run
+    token: CancellationToken,
+    /// cancelled once all downloads have stopped
+    done: CancellationToken,
+    state: ListState,
+    /// per task: downloaded bytes at the last tick and the smoothed speed in bytes/s
+    speeds: Vec<(u64, f64)>,
+    last_tick: Instant,
+}
+
+impl<'d> Dashboard<'d> {
+    pub fn new(dl: &'d DownloadTask, token: CancellationToken, done: CancellationToken) -> Self {
+        Self {
+            dl,
+            token,
+            done,
+            state: ListState::default().with_selected(Some(0)),
+            speeds: vec![(0, 0.0); dl.tasks.len()],
+            last_tick: Instant::now(),
+        }
+    }
+
+    /// show the dashboard until all downloads have stopped
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut terminal = init_terminal()?;
+        let res = self.event_loop(&mut terminal);
+        restore()?;
+        res
+    }
+
+    fn event_loop(&mut self, terminal: &mut Tui) -> io::Result<()> {
+        while !self.done.is_cancelled() {
+            self.update_speeds();
+            terminal.draw(|f| f.render_widget(&mut *self, f.area()))?;
+            if event::poll(TICK)? {
+                if let Event::Key(key) = event::read()? {
+                    self.handle_key(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> io::Result<()> {
+        if key.kind != KeyEventKind::Press {
+            return Ok(());
+        }
+        let ctrl_c =
+            key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+        if ctrl_c || key.code == KeyCode::Char('q') {
+            if ctrl_c && self.token.is_cancelled() {
+                // the second Ctrl-C exits immediately
+                restore()?;
+                eprintln!("task canceled by user");
+                std::process::exit(130);
+            }
+            if !self.token.is_cancelled() {
+                info!("正在停止下载并保存进度，再次按 Ctrl-C 立即退出");
+                self.token.cancel();
+            }
+            return Ok(());
+        }
+
+        let order = self.dl.order();
+        let Some(pos) = self.state.selected().filter(|&p| p < order.len()) else {
+            self.state.select_first();
+            return Ok(());
+        };
+        let task = &self.dl.tasks[order[pos]];
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            KeyCode::Up if shift => self.move_selected(pos, -1),
+            KeyCode::Down if shift => self.move_selected(pos, 1),
+            KeyCode::Char('K') => self.move_selected(pos, -1),
+            KeyCode::Char('J') => self.move_selected(pos, 1),
+            KeyCode::Up | KeyCode::Char('k') => self.state.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.state.select_next(),
+            KeyCode::Char('p') | KeyCode::Char(' ') => match task.state() {
+                TaskState::Paused => self.dl.resume(task),
+                _ => self.dl.pause(task),
+            },
+            KeyCode::Char('r') => {
+                if matches!(task.state(), TaskState::Failed(_) | TaskState::Cancelled) {
+                    self.dl.resume(task);
+                }
+            }
+            KeyCode::Char('x') => self.dl.cancel(task),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn move_selected(&mut self, pos: usize, offset: isize) {
+        self.dl.move_task(pos, offset);
+        if let Some(p) = pos.checked_add_signed(offset) {
+            self.state.select(Some(p.min(self.dl.tasks.len() - 1)));
+        }
+    }
+
+    fn update_speeds(&mut self) {
+        let elapsed = self.last_tick.elapsed().as_secs_f64();
+        if elapsed < TICK.as_secs_f64() {
+            return;
+        }
+        self.last_tick = Instant::now();
+        for (task, (last, speed)) in self.dl.tasks.iter().zip(self.speeds.iter_mut()) {
+            // the bytes resumed from a checkpoint arrive at once and are no speed
+            let bytes = task.progress.transferred();
+            let current = bytes.saturating_sub(*last) as f64 / elapsed;
+            *speed = if task.state() == TaskState::Downloading {
+                // exponential moving average to keep the numbers readable
+                *speed * 0.7 + current * 0.3
+            } else {
+                0.0
+            };
+            *last = bytes;
+        }
+    }
+
+    fn task_line(&self, i: usize) -> ListItem<'static> {
+        let task = &self.dl.tasks[i];
+        let (state, color) = match task.state() {
+            TaskState::Queued => ("等待中".to_string(), Color::Rgb(120, 144, 156)),
+            TaskState::Downloading => ("下载中".to_string(), Color::Rgb(255, 167, 38)),
+            TaskState::Merging => ("合并中".to_string(), Color::Rgb(255, 167, 38)),
+            TaskState::Paused => ("已暂停".to_string(), Color::Rgb(120, 144, 156)),
+            TaskState::Finished => ("已完成".to_string(), Color::Rgb(102, 187, 106)),
+            TaskState::Skipped => ("已跳过".to_string(), Color::Rgb(102, 187, 106)),
+            TaskState::Failed(e) => (format!("失败: {}", e), Color::Rgb(239, 83, 80)),
+            TaskState::Cancelled => ("已取消".to_string(), Color::Rgb(120, 144, 156)),
+        };
+        let (bytes, total) = task.progress.bytes();
+        let speed = self.speeds[i].1;
+        let mut content = format!("{} [{}]", task.title, state);
+        if let Some(quality) = task.quality() {
+            content.push_str(&format!(" {}", quality));
+        }
+        if total > 0 {
+            content.push_str(&format!(
+                " {}/{}",
+                format_bytes(bytes as f64),
+                format_bytes(total as f64)
+            ));
+        }
+        if speed > 0.0 {
+            let eta = total.saturating_sub(bytes) as f64 / speed;
+            content.push_str(&format!(
+                " {}/s ETA {}",
+                format_bytes(speed),
+                format_duration(eta)
+            ));
+        }
+        ListItem::new(content).style(Style::default().fg(color))
+    }
+}
+
+impl Widget for &mut Dashboard<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let [main_area, log_area, foot_area] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(LOG_HEIGHT),
+            Constraint::Length(2),
+        ])
+        .areas(area);
+
+        let items: Vec<ListItem> = self
+            .dl
+            .order()
+            .into_iter()
+            .map(|i| self.task_line(i))
+            .collect();
+        let list = List::new(items)
+            .block(Block::new().title("Download").borders(Borders::ALL))
+            .highlight_symbol(">> ")
+            .highlight_spacing(HighlightSpacing::Always);
+        StatefulWidget::render(list, main_area, buf, &mut self.state);
+
+        let logs: Vec<Line> = progress::last_logs(LOG_HEIGHT as usize - 2)
+            .into_iter()
+            .map(Line::from)
+            .collect();
+        Paragraph::new(logs)
+            .block(Block::new().borders(Borders::ALL))
+            .render(log_area, buf);
+
+        let active = self.dl.tasks.iter().any(|t| {
+            matches!(
+                t.state(),
+                TaskState::Queued | TaskState::Downloading | TaskState::Merging
+            )
+        });
+        let help = if self.token.is_cancelled() {
+            "正在停止下载…… <Ctrl-C>: 立即退出"
+        } else if !active {
+            "下载已结束 <↑/↓>: 上下移动; <p>: 继续; <r>: 重试; <q>: 退出"
+        } else {
+            "<↑/↓>: 上下移动; <Shift+↑/↓>: 调整顺序; <p>: 暂停 / 继续; <r>: 重试; <x>: 取消; <q>: 停止全部"
+        };
+        Paragraph::new(help).centered().render(foot_area, buf);
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
 fn init_terminal() -> io::Result<Tui> {
     execute!(stdout(), EnterAlternateScreen)?;
     enable_raw_mode()?;
@@ -209,3 +1069,184 @@ pub fn select_download_video(video_list: Vec<Task>, flag: Vec<bool>) -> Vec<Task
         .map(|(video, _)| video)
         .collect()
 }
+
+/// 非交互模式下的选择：下载全部或 `--items` 指定的视频，跳过下载记录中已有的视频
+pub fn auto_selection(video_list: &[Task], items: Option<&Items>) -> Vec<bool> {
+    video_list
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let selected = !v.archived && items.is_none_or(|it| it.contains(i + 1));
+            let is_checked = if selected { "[✓]" } else { "[ ]" };
+            let archived = if v.archived { " [已下载]" } else { "" };
+            info!("{} {:>3}. {}{}", is_checked, i + 1, v.title, archived);
+            selected
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tui_test {
+    use super::*;
+    use crate::http::download::TaskKey;
+    use crate::parser::parser_test::stream;
+
+    fn press(ui: &mut SelectionUI, keys: &str) {
+        for c in keys.chars() {
+            ui.handle_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+    }
+
+    #[test]
+    fn search_and_range() {
+        let tasks: Vec<Task> = ["第1话", "第2话", "PV", "第3话", "SP"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                Task::new(
+                    String::new(),
+                    t.to_string(),
+                    i + 1,
+                    TaskKey::episode(i as i64),
+                )
+            })
+            .collect();
+        let mut ui = SelectionUI::new(&tasks);
+        assert_eq!(ui.video_list.selected_count(), 5);
+
+        // deselect the two episodes after the first one with the visual mode
+        press(&mut ui, "jvjd");
+        assert_eq!(ui.video_list.is_selected, [true, false, false, true, true]);
+
+        // invert only the episodes matching the search
+        press(&mut ui, "/话");
+        ui.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(ui.video_list.visible, [0, 1, 3]);
+        press(&mut ui, "i");
+        assert_eq!(ui.video_list.is_selected, [false, true, false, false, true]);
+
+        ui.handle_key(KeyEvent::from(KeyCode::Esc));
+        assert_eq!(ui.video_list.visible.len(), 5);
+        ui.handle_key(KeyEvent::from(KeyCode::End));
+        ui.handle_key(KeyEvent::new(KeyCode::Up, KeyModifiers::SHIFT));
+        press(&mut ui, " ");
+        assert_eq!(ui.get_selection(), [false, true, false, false, false]);
+    }
+
+    #[test]
+    fn locked_member_videos() {
+        let mut tasks: Vec<Task> = (0..3)
+            .map(|i| Task::new(String::new(), i.to_string(), i, TaskKey::episode(i as i64)))
+            .collect();
+        tasks[1].details.access = Access::Member;
+        let mut ui = SelectionUI::new(&tasks).vip(false);
+        assert_eq!(ui.video_list.is_selected, [true, false, true]);
+        press(&mut ui, "jdai");
+        assert_eq!(ui.video_list.is_selected, [true, false, true]);
+
+        let ui = SelectionUI::new(&tasks).vip(true);
+        assert_eq!(ui.get_selection(), [true, true, true]);
+    }
+
+    #[test]
+    fn songs_have_no_formats() {
+        let mut tasks: Vec<Task> = (0..2)
+            .map(|i| Task::new(String::new(), i.to_string(), i, TaskKey::song(i as i64)))
+            .collect();
+        tasks[0].song = Some(Default::default());
+        let mut ui = SelectionUI::new(&tasks);
+        assert!(matches!(ui.formats[0], FormatState::Song));
+        assert!(matches!(ui.formats[1], FormatState::Pending));
+        press(&mut ui, "f");
+        assert!(ui.picker.is_none());
+        press(&mut ui, "jf");
+        assert!(ui.picker.is_some());
+    }
+
+    #[tokio::test]
+    async fn fetch_on_rest_or_picker() {
+        let tasks: Vec<Task> = (0..3)
+            .map(|i| {
+                Task::new(
+                    String::new(),
+                    i.to_string(),
+                    i,
+                    TaskKey::video("BV", i as i64),
+                )
+            })
+            .collect();
+        let mut ui = SelectionUI::new(&tasks);
+        let (wanted_tx, mut wanted_rx) = tokio_mpsc::unbounded_channel();
+        let (_results_tx, results_rx) = mpsc::channel();
+        ui.fetcher = Some(Fetcher {
+            wanted: wanted_tx,
+            results: results_rx,
+            highlighted: None,
+            requested: vec![false; tasks.len()],
+            handle: tokio::spawn(async {}),
+        });
+        // moving past a video does not fetch it
+        ui.update_formats();
+        press(&mut ui, "j");
+        ui.update_formats();
+        assert!(wanted_rx.try_recv().is_err());
+        // resting on it does, once
+        ui.fetcher.as_mut().unwrap().highlighted = Some((1, Instant::now() - SETTLE));
+        ui.update_formats();
+        ui.update_formats();
+        assert_eq!(wanted_rx.try_recv().ok(), Some(1));
+        assert!(wanted_rx.try_recv().is_err());
+        // opening the picker fetches right away
+        press(&mut ui, "jf");
+        assert_eq!(wanted_rx.try_recv().ok(), Some(2));
+    }
+
+    #[test]
+    fn pick_format() {
+        let play_info = serde_json::json!({
+            "accept_quality": [120, 80, 64],
+            "accept_description": ["4K 超清", "1080P 高清", "720P 高清"],
+            "accept_format": "",
+            "dash": {
+                "duration": 100,
+                "video": [
+                    stream(80, 2_000_000, "avc1.640032", 1080),
+                    stream(120, 8_000_000, "hev1.1.6.L150.90", 2160),
+                    stream(64, 1_000_000, "avc1.64001F", 720),
+                ],
+                "audio": [stream(30216, 64_000, "mp4a", 0), stream(30280, 192_000, "mp4a", 0)],
+                "dolby": {"type": 0, "audio": null},
+            },
+        });
+        let formats = Formats::new(serde_json::from_value(play_info).unwrap());
+        let tasks = vec![Task::new(
+            String::new(),
+            "第1话".to_owned(),
+            1,
+            TaskKey::episode(1),
+        )];
+        let mut ui = SelectionUI::new(&tasks);
+        ui.formats[0] = FormatState::Loaded(formats);
+        assert_eq!(ui.estimated_size(0), Some((8_000_000 + 192_000) * 100 / 8));
+
+        // 720p with the lower audio quality
+        press(&mut ui, "fjjlj");
+        ui.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(
+            tasks[0].format(),
+            FormatChoice {
+                video: Some((64, "avc1.64001F".to_owned())),
+                audio: Some(30216),
+            }
+        );
+        assert_eq!(ui.estimated_size(0), Some((1_000_000 + 64_000) * 100 / 8));
+
+        // cancelling keeps the choice, choosing the best streams resets it
+        press(&mut ui, "fk");
+        ui.handle_key(KeyEvent::from(KeyCode::Esc));
+        assert_eq!(tasks[0].format().audio, Some(30216));
+        press(&mut ui, "fkkhk");
+        ui.handle_key(KeyEvent::from(KeyCode::Enter));
+        assert_eq!(tasks[0].format(), FormatChoice::default());
+    }
+}
@@ -0,0 +1,250 @@
+use crate::http::checkpoint::Checkpoint;
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const RUN_PREFIX: &str = "run-";
+const RUN_LOCK: &str = "run.lock";
+const LOCKS_DIR: &str = "locks";
+
+/// 保存下载中音视频流的工作目录
+///
+/// 每次运行使用 `<base>/run-<pid>-<时间戳>` 保存临时文件，并在运行期间锁住其中的 `run.lock`。
+/// 进程退出后锁会自动释放，所以没有被锁住的运行目录都是残留的临时文件，
+/// 其中带有下载进度的音视频会在下次下载同一视频时被继续使用
+#[derive(Debug)]
+pub struct WorkDir {
+    base: PathBuf,
+    run_dir: PathBuf,
+    _lock: File,
+}
+
+/// 正在下载的视频的锁，避免多个 bili-dl 进程同时下载同一个视频，drop 时释放
+#[derive(Debug)]
+pub struct TaskLock {
+    _file: File,
+}
+
+impl WorkDir {
+    /// 默认的工作目录，如 `~/.cache/bili-dl`
+    pub fn default_base() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("bili-dl")
+    }
+
+    /// 在 `base` 下新建本次运行的目录
+    pub fn create(base: PathBuf) -> io::Result<Self> {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let run_dir = base.join(format!("{}{}-{}", RUN_PREFIX, std::process::id(), ts));
+        fs::create_dir_all(&run_dir)?;
+        let lock = File::create(run_dir.join(RUN_LOCK))?;
+        lock.try_lock()?;
+        Ok(Self {
+            base,
+            run_dir,
+            _lock: lock,
+        })
+    }
+
+    pub fn run_dir(&self) -> &Path {
+        &self.run_dir
+    }
+
+    /// the temporary file of a media stream, unique within the run
+    pub fn media_path(&self, key: &str, media: &str) -> PathBuf {
+        self.run_dir.join(format!("{}.{}.m4s", key, media))
+    }
+
+    /// lock the task with `key` across processes, `None` if another process holds the lock
+    pub fn lock_task(&self, key: &str) -> io::Result<Option<TaskLock>> {
+        let dir = self.base.join(LOCKS_DIR);
+        fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(format!("{}.lock", key)))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(TaskLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// move an unfinished download of the stream left by an exited process into this run,
+    /// returning whether one was found
+    pub fn adopt(&self, key: &str, media: &str) -> bool {
+        let target = self.media_path(key, media);
+        let Ok(entries) = fs::read_dir(&self.base) else {
+            return false;
+        };
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            let is_run = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|n| n.starts_with(RUN_PREFIX));
+            if !is_run || dir == self.run_dir {
+                continue;
+            }
+            let source = dir.join(target.file_name().unwrap());
+            if !Checkpoint::path(&source).exists() {
+                continue;
+            }
+            // hold the lock of the orphan so that only one process takes it over
+            let Ok(lock) = File::open(dir.join(RUN_LOCK)) else {
+                continue;
+            };
+            if lock.try_lock().is_err() {
+                continue;
+            }
+            let moved = fs::rename(&source, &target)
+                .and_then(|_| fs::rename(Checkpoint::path(&source), Checkpoint::path(&target)));
+            if moved.is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// remove the temporary files of the run, except unfinished downloads with a checkpoint.
+    /// Returns whether anything was kept
+    pub fn finish(&self) -> io::Result<bool> {
+        let mut kept = false;
+        for entry in fs::read_dir(&self.run_dir)? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|n| n == RUN_LOCK) {
+                continue;
+            }
+            let media = if path.extension().is_some_and(|e| e == "json") {
+                path.with_extension("")
+            } else {
+                path.clone()
+            };
+            if media.exists() && Checkpoint::path(&media).exists() {
+                kept = true;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+        if !kept {
+            fs::remove_dir_all(&self.run_dir)?;
+        }
+        Ok(kept)
+    }
+}
+
+/// 删除 `base` 下已退出的进程留下的运行目录和锁文件，返回删除的运行目录数量
+pub fn cleanup(base: &Path) -> io::Result<usize> {
+    let entries = match fs::read_dir(base) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let is_run = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|n| n.starts_with(RUN_PREFIX));
+        if !is_run || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        if is_locked(&path.join(RUN_LOCK))? {
+            continue;
+        }
+        fs::remove_dir_all(&path)?;
+        removed += 1;
+    }
+
+    if let Ok(locks) = fs::read_dir(base.join(LOCKS_DIR)) {
+        for entry in locks {
+            let path = entry?.path();
+            if !is_locked(&path)? {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// whether another open file holds the lock on `path`
+fn is_locked(path: &Path) -> io::Result<bool> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    match file.try_lock() {
+        Ok(()) => Ok(false),
+        Err(TryLockError::WouldBlock) => Ok(true),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod workdir_test {
+    use super::*;
+    use crate::http::test_server::temp_dir;
+
+    #[test]
+    fn cleanup_orphans() {
+        let base = temp_dir("workdir");
+        let orphan = base.join("run-1-0");
+        fs::create_dir_all(&orphan).unwrap();
+        fs::write(orphan.join(RUN_LOCK), "").unwrap();
+        fs::write(orphan.join("ep1.video.m4s"), "data").unwrap();
+
+        let work_dir = WorkDir::create(base.clone()).unwrap();
+        let path = work_dir.media_path("ep1", "video");
+        fs::write(&path, "data").unwrap();
+
+        let lock = work_dir.lock_task("ep1").unwrap();
+        assert!(lock.is_some());
+        assert!(work_dir.lock_task("ep1").unwrap().is_none());
+        drop(lock);
+        assert!(work_dir.lock_task("ep1").unwrap().is_some());
+
+        // only the orphan is removed, the running one is still locked
+        assert_eq!(cleanup(&base).unwrap(), 1);
+        assert!(!orphan.exists());
+        assert!(path.exists());
+
+        assert!(!work_dir.finish().unwrap());
+        drop(work_dir);
+        assert_eq!(cleanup(&base).unwrap(), 0);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn adopt_unfinished() {
+        let base = temp_dir("adopt");
+        let orphan = base.join("run-1-0");
+        fs::create_dir_all(&orphan).unwrap();
+        fs::write(orphan.join(RUN_LOCK), "").unwrap();
+        fs::write(orphan.join("ep1.video.m4s"), "data").unwrap();
+        fs::write(orphan.join("ep1.video.m4s.json"), "{}").unwrap();
+        fs::write(orphan.join("ep1.audio.m4s"), "data").unwrap();
+
+        let work_dir = WorkDir::create(base.clone()).unwrap();
+        assert!(work_dir.adopt("ep1", "video"));
+        // no checkpoint, the download can not be resumed
+        assert!(!work_dir.adopt("ep1", "audio"));
+        assert!(!work_dir.adopt("ep2", "video"));
+        assert!(work_dir.media_path("ep1", "video").exists());
+
+        assert!(work_dir.finish().unwrap());
+        assert!(work_dir.media_path("ep1", "video").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}